@@ -0,0 +1,100 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+/*
+ * A stand-in for the MinIO admin trace API, for trying out minio-statemap's
+ * --endpoint option without a MinIO cluster. Each request to the trace API is
+ * answered by replaying a canned trace capture, one record per line, and then
+ * closing the connection.
+ *
+ *     cargo run --example trace_stub -- my_trace 127.0.0.1:9000
+ *     minio-statemap --endpoint http://127.0.0.1:9000 > minio_states
+ *
+ * The request's Authorization header is printed but not checked.
+ *
+ * minio-statemap's tests also use serve() to check the records that come
+ * through its admin client.
+ */
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/* Pause between records so the replay looks like a live trace. */
+const RECORD_DELAY: Duration = Duration::from_millis(10);
+
+/*
+ * Answer one request, returning its request line and Authorization header.
+ */
+pub fn serve(stream: TcpStream, trace: &str)
+    -> std::io::Result<(String, Option<String>)> {
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let request_line = request_line.trim().to_string();
+    eprintln!("trace_stub: {}", request_line);
+
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if header.to_lowercase().starts_with("authorization:") {
+            eprintln!("trace_stub: {}", header.trim());
+            authorization = Some(header["authorization:".len()..].trim()
+                .to_string());
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if !path.starts_with("/minio/admin/v3/trace") {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\n\
+            Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+        return Ok((request_line, authorization));
+    }
+
+    stream.write_all(b"HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\nConnection: close\r\n\r\n")?;
+
+    for line in trace.lines() {
+        stream.write_all(line.as_bytes())?;
+        stream.write_all(b"\n")?;
+        thread::sleep(RECORD_DELAY);
+    }
+
+    Ok((request_line, authorization))
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: trace_stub TRACE_FILE [ADDRESS]");
+        std::process::exit(2);
+    }
+
+    let trace = fs::read_to_string(&args[1])?;
+    let addr = args.get(2).map(|a| a.as_str()).unwrap_or("127.0.0.1:9000");
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("trace_stub: replaying {} on {}", args[1], addr);
+
+    for stream in listener.incoming() {
+        if let Err(e) = serve(stream?, &trace) {
+            eprintln!("trace_stub: {}", e);
+        }
+    }
+
+    Ok(())
+}
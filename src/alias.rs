@@ -0,0 +1,180 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use regex::Regex;
+
+use crate::trace::TraceData;
+
+/*
+ * Aliases gives servers and clients friendlier names than the addresses
+ * MinIO reports, e.g. 'minio3' rather than '172.20.0.5:9000'.
+ *
+ * A name with an exact alias is replaced by it. Otherwise each rewrite rule
+ * is applied in turn, regex replacement style, so one rule can cover a whole
+ * cluster: '^172\.20\.0\.(\d+):9000$' => 'minio$1'.
+ */
+#[derive(Default)]
+pub struct Aliases {
+    exact: HashMap<String, String>,
+    rewrites: Vec<(Regex, String)>,
+}
+
+impl Aliases {
+    /*
+     * Read an alias file. Each line holds a name and its alias separated by
+     * whitespace. Lines starting with '~' are rewrite rules instead, with a
+     * regex in place of the name. Blank lines and '#' comments are ignored.
+     *
+     *     172.20.0.5:9000        minio3
+     *     ~^172\.20\.0\.(\d+):   node$1:
+     */
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.parse(path, &text)
+    }
+
+    fn parse(&mut self, path: &str, text: &str) -> io::Result<()> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |why: String| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} line {}: {}", path, n + 1, why));
+
+            let mut fields = line.split_whitespace();
+            let (from, to) = match (fields.next(), fields.next(),
+                fields.next()) {
+                (Some(from), Some(to), None) => (from, to),
+                _ => return Err(invalid("expected a name and an alias"
+                    .to_string())),
+            };
+
+            match from.strip_prefix('~') {
+                Some(pattern) => self.add_rewrite(pattern, to)
+                    .map_err(invalid)?,
+                None => {
+                    self.exact.insert(from.to_string(), to.to_string());
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Add a rewrite rule given on the command line as PATTERN=REPLACEMENT.
+     */
+    pub fn add_rule(&mut self, rule: &str) -> Result<(), String> {
+        match rule.rfind('=') {
+            Some(i) => self.add_rewrite(&rule[..i], &rule[i + 1..]),
+            None => Err(format!("invalid rewrite rule '{}': expected \
+                'PATTERN=REPLACEMENT'", rule)),
+        }
+    }
+
+    fn add_rewrite(&mut self, pattern: &str, replacement: &str)
+        -> Result<(), String> {
+
+        let re = Regex::new(pattern)
+            .map_err(|e| format!("invalid rewrite pattern '{}': {}", pattern,
+                e))?;
+        self.rewrites.push((re, replacement.to_string()));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.rewrites.is_empty()
+    }
+
+    pub fn name(&self, raw: &str) -> String {
+        if let Some(alias) = self.exact.get(raw) {
+            return alias.clone();
+        }
+
+        let mut name = raw.to_string();
+        for (re, replacement) in &self.rewrites {
+            name = re.replace(&name, replacement.as_str()).into_owned();
+        }
+        name
+    }
+
+    /*
+     * Rename the server and client of a trace record. This is done as records
+     * are read, so every entity and report sees the same names.
+     */
+    pub fn apply(&self, mut td: TraceData) -> TraceData {
+        td.host = self.name(&td.host);
+        if !td.client.is_empty() {
+            td.client = self.name(&td.client);
+        }
+        td
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALIASES: &str = r#"
+        # The load balancer
+        172.20.0.1:9000         lb
+
+        ~^172\.20\.0\.(\d+):     minio$1:
+        ~^minio(\d+):9000$      m$1
+    "#;
+
+    fn aliases() -> Aliases {
+        let mut aliases = Aliases::default();
+        aliases.parse("aliases", ALIASES).unwrap();
+        aliases
+    }
+
+    #[test]
+    fn prefers_exact_alias() {
+        assert_eq!(aliases().name("172.20.0.1:9000"), "lb");
+    }
+
+    #[test]
+    fn chains_rewrites() {
+        let aliases = aliases();
+        assert_eq!(aliases.name("172.20.0.5:9000"), "m5");
+        assert_eq!(aliases.name("172.20.0.5:9001"), "minio5:9001");
+        assert_eq!(aliases.name("10.0.0.1:9000"), "10.0.0.1:9000");
+    }
+
+    #[test]
+    fn splits_rule_on_last_equals() {
+        let mut aliases = Aliases::default();
+        aliases.add_rule("^(a=b)$=<$1>").unwrap();
+        assert_eq!(aliases.name("a=b"), "<a=b>");
+        assert!(aliases.add_rule("no rule").is_err());
+        assert!(aliases.add_rule("(=x").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        for text in &["minio1", "minio1 m1 extra", "~( m1"] {
+            let err = Aliases::default().parse("aliases", text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().starts_with("aliases line 1: "), "{}",
+                err);
+        }
+
+        let err = Aliases::default().parse("aliases", "\nminio1\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+            "aliases line 2: expected a name and an alias");
+    }
+}
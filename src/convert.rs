@@ -0,0 +1,494 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use statemap::Statemap;
+
+use crate::correlate::Lifecycles;
+use crate::drive::Drives;
+use crate::entity::EntityKind;
+use crate::failure::Failures;
+use crate::gaps::Gaps;
+use crate::lanes::Lanes;
+use crate::links::LinkMatrix;
+use crate::order::{EntityOrder, SortKey};
+use crate::overlap::Overlaps;
+use crate::rules::Rules;
+use crate::tags::Tags;
+use crate::topology::Topology;
+use crate::trace::TraceData;
+use crate::window::Window;
+
+/*
+ * Converter turns MinIO trace records into statemap states according to the
+ * user's options.
+ */
+pub struct Converter {
+    pub title: String,
+    pub cluster: String,
+    pub entity: EntityKind,
+    pub window: Window,
+    /* Set if overlapping operations should be split into lanes. */
+    pub lanes: Option<Lanes>,
+    pub overlaps: Overlaps,
+    /* Calls waiting for their S3 request, for the request entity. */
+    pub lifecycles: Lifecycles,
+    /* Set if a summary of calls between each caller and server is wanted. */
+    pub matrix: Option<LinkMatrix>,
+    /* Set if entities should be grouped by pool and erasure set. */
+    pub topology: Option<Topology>,
+    /* Where each server's drives are mounted, as far as we know. */
+    pub drives: Drives,
+    /* Set if the statemap's entities should be sorted and grouped. */
+    pub order: Option<EntityOrder>,
+    /* What state each call is drawn in, and the states' colors. */
+    pub rules: Rules,
+    /* Failed calls' states, and how many there were on each entity. */
+    pub failures: Failures,
+    /* Set if states should be tagged with the details of their calls. */
+    pub tags: Option<Tags>,
+    /* Set if calls should be split at their first byte. */
+    pub phases: bool,
+    /* What state each entity is drawn in between calls. */
+    pub gaps: Gaps,
+    /* Every state drawn so far, in the order of the values they're given. */
+    pub states: Vec<String>,
+}
+
+impl Converter {
+    pub fn statemap(&self) -> Statemap {
+        Statemap::new(&self.title, Some(self.cluster.clone()),
+            Some(self.entity.description().to_string()))
+    }
+
+    /*
+     * Record the states for a single MinIO trace record.
+     */
+    pub fn record(&mut self, sm: &mut Statemap, td: &TraceData)
+        -> io::Result<()> {
+
+        let (begin, end) = match self.window.clip(td.start_time(), td.time) {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+
+        if let Some(matrix) = &mut self.matrix {
+            matrix.add(td, begin, end);
+        }
+
+        if !self.entity.includes(td) {
+            return Ok(());
+        }
+
+        /*
+         * Normally a record is a single state, but in the request view all
+         * of an S3 request's calls are drawn together once it completes.
+         * Calls that no request accounts for are left out of that view.
+         */
+        let state = self.rules.state(td);
+        let first_byte = td.first_byte().filter(|_| self.phases &&
+            self.split(td));
+        let drawn = match first_byte {
+            Some(_) => state.clone(),
+            None => self.failures.state(td, state.clone()),
+        };
+        let mut states = match self.entity {
+            EntityKind::Request => match self.lifecycles.add(td, drawn) {
+                Some(states) => states,
+                None => return Ok(()),
+            },
+            _ => vec![(td.start_time(), drawn)],
+        };
+
+        /*
+         * With phases, a call is drawn in one state while the server works
+         * out its response and another while the response is sent.
+         */
+        if let Some(first_byte) = first_byte {
+            let processing = self.failures.state(td,
+                format!("{}:processing", state));
+            let transfer = self.failures.state(td,
+                format!("{}:transfer", state));
+            states = split_phases(states, &state, first_byte, processing,
+                transfer);
+        }
+
+        self.drives.learn(td);
+
+        let mut entity = self.entity.entity(td, &self.drives);
+        if let Some(topology) = &self.topology {
+            entity = topology.place(self.entity, td, &self.drives, entity);
+        }
+        let base = entity.clone();
+        self.failures.count(&base, td);
+        if let Some(lanes) = &mut self.lanes {
+            entity = lanes.assign(&entity, begin, end);
+        }
+
+        let split = self.lanes.is_some();
+        if let Some(order) = &mut self.order {
+            /*
+             * Lanes split from the same entity are grouped together unless
+             * the entity belongs to a larger group.
+             */
+            let group = match (&self.topology, order.key()) {
+                (Some(t), SortKey::Topology) =>
+                    t.group(self.entity, td, &self.drives),
+                _ => self.entity.group(td).or(split.then_some(base)),
+            };
+            order.add(&entity, group, td, begin, end);
+        }
+
+        self.overlaps.check(&entity, &td.host, &td.api, begin, end)?;
+
+        /*
+         * Set this entity to be working on the given API request, ending
+         * the gap since its last one. Once it's done, a new gap opens.
+         * States from before the window opened are moved up to its start,
+         * where only the last of them matters. Any tags describe this
+         * record, which in the request view is the request.
+         */
+        let mut clipped: Vec<(DateTime<Utc>, String)> = Vec::new();
+        for (t, state) in states {
+            if t > end {
+                break;
+            }
+            if t <= begin {
+                clipped.clear();
+            }
+            clipped.push((t.max(begin), state));
+        }
+        self.gaps.close(sm, &entity, begin);
+        let mut tagged: Vec<(String, String)> = Vec::new();
+        for (t, state) in clipped {
+            let tag = self.tags.as_mut().map(|tags| {
+                match tagged.iter().find(|(s, _)| *s == state) {
+                    Some((_, tag)) => tag.clone(),
+                    None => {
+                        let tag = tags.tag(&state, td);
+                        tagged.push((state.clone(), tag.clone()));
+                        tag
+                    },
+                }
+            });
+            sm.set_state(&entity, &state, tag, t);
+        }
+        self.gaps.open(&entity, end);
+
+        Ok(())
+    }
+
+    /*
+     * Whether a record is drawn by itself, so it can be split at its first
+     * byte. In the request view, calls are only drawn as part of their
+     * request, and it's the request that's split.
+     */
+    fn split(&self, td: &TraceData) -> bool {
+        !matches!(self.entity, EntityKind::Request) || td.trace_type == "s3"
+    }
+
+    /*
+     * Print a statemap's metadata and states.
+     */
+    pub fn emit(&mut self, mut sm: Statemap, out: &mut dyn Write)
+        -> io::Result<()> {
+
+        self.gaps.print(&mut sm);
+
+        let mut states = sm.into_iter();
+        let metadata = match states.next() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        let (metadata, values) = self.metadata(metadata);
+        writeln!(out, "{}", metadata)?;
+
+        /*
+         * Tags are defined in terms of the values the statemap gave
+         * their states, which are only known from its metadata.
+         */
+        if let Some(tags) = &mut self.tags {
+            let states = serde_json::from_str::<Value>(&metadata)
+                .map(|mut m| m["states"].take())
+                .unwrap_or(Value::Null);
+            tags.print(&states, out)?;
+        }
+
+        let states = states.map(|s| renumber(s, &values));
+        match &mut self.order {
+            Some(order) => order.print(states, out)?,
+            None => {
+                for state in states {
+                    writeln!(out, "{}", state)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Print any summaries that were asked for to stderr, once the input has
+     * been exhausted.
+     */
+    pub fn finish(&self) -> io::Result<()> {
+        if let Some(matrix) = &self.matrix {
+            matrix.print(&mut io::stderr())?;
+        }
+        self.failures.print(&mut io::stderr())?;
+
+        Ok(())
+    }
+
+    /*
+     * The states' colors: those of gaps and failed calls, unless the rules
+     * say otherwise.
+     */
+    fn colors(&self) -> HashMap<&str, &str> {
+        let mut colors = HashMap::new();
+        colors.extend(self.gaps.colors()
+            .map(|(s, c)| (s.as_str(), c.as_str())));
+        colors.extend(self.failures.colors().map(|(s, c)| (s.as_str(), *c)));
+        colors.extend(self.rules.colors()
+            .map(|(s, c)| (s.as_str(), c.as_str())));
+        colors
+    }
+
+    /*
+     * The statemap crate has no notion of the extra metadata we want to
+     * record, so add it to the metadata it generates.
+     *
+     * Each statemap the crate makes numbers its own states. When one is
+     * printed every second they must agree, so states are renumbered in
+     * the order they were first seen over the whole run, and every state
+     * seen so far is described. Returns the new metadata and the new value
+     * for each of the statemap's own, if any have changed.
+     */
+    fn metadata(&mut self, metadata: String) -> (String, Vec<u64>) {
+        let mut value: Value = match serde_json::from_str(&metadata) {
+            Ok(v) => v,
+            Err(_) => return (metadata, Vec::new()),
+        };
+
+        if let Some(window) = self.window.metadata() {
+            value["window"] = window;
+        }
+
+        let mut values = Vec::new();
+        if let Some(states) = value["states"].as_object() {
+            for (state, desc) in states {
+                let old = match desc["value"].as_u64() {
+                    Some(v) => v as usize,
+                    None => continue,
+                };
+                let new = match self.states.iter().position(|s| s == state) {
+                    Some(i) => i,
+                    None => {
+                        self.states.push(state.clone());
+                        self.states.len() - 1
+                    },
+                };
+                if values.len() <= old {
+                    values.resize(old + 1, 0);
+                }
+                values[old] = new as u64;
+            }
+        }
+
+        let colors = self.colors();
+        let states: Map<String, Value> = self.states.iter().enumerate()
+            .map(|(i, state)| {
+                let mut desc = json!({ "value": i });
+                if let Some(color) = colors.get(state.as_str()) {
+                    desc["color"] = json!(color);
+                }
+                (state.clone(), desc)
+            })
+            .collect();
+        value["states"] = Value::Object(states);
+
+        if values.iter().enumerate().all(|(i, v)| i as u64 == *v) {
+            values.clear();
+        }
+
+        (value.to_string(), values)
+    }
+}
+
+/*
+ * Give a state record from the statemap crate the value its state has over
+ * the whole run.
+ */
+fn renumber(record: String, values: &[u64]) -> String {
+    if values.is_empty() {
+        return record;
+    }
+
+    let mut value: Value = match serde_json::from_str(&record) {
+        Ok(v) => v,
+        Err(_) => return record,
+    };
+    let new = value["state"].as_u64()
+        .and_then(|s| values.get(s as usize));
+    match new {
+        Some(new) => {
+            value["state"] = json!(new);
+            value.to_string()
+        },
+        None => record,
+    }
+}
+
+/*
+ * Split the time a call spends in 'state' at its first byte: before it, the
+ * call is 'processing', and after it, 'transfer'. Other states, such as those
+ * of the calls an S3 request made in the request view, are left as they are.
+ */
+fn split_phases(states: Vec<(DateTime<Utc>, String)>, state: &str,
+    first_byte: DateTime<Utc>, processing: String, transfer: String)
+    -> Vec<(DateTime<Utc>, String)> {
+
+    let mut split = Vec::new();
+    let mut current: Option<String> = None;
+    let mut crossed = false;
+
+    for (t, s) in states {
+        if !crossed && t >= first_byte {
+            crossed = true;
+            if t > first_byte && current.as_deref() == Some(state) {
+                split.push((first_byte, transfer.clone()));
+            }
+        }
+
+        let name = match (s == state, t < first_byte) {
+            (true, true) => processing.clone(),
+            (true, false) => transfer.clone(),
+            (false, _) => s.clone(),
+        };
+        current = Some(s);
+        split.push((t, name));
+    }
+
+    if !crossed && current.as_deref() == Some(state) {
+        split.push((first_byte, transfer));
+    }
+
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+
+    use super::*;
+    use crate::trace::testing::{at, call};
+
+    fn converter(entity: EntityKind, phases: bool) -> Converter {
+        Converter {
+            title: "test".to_string(),
+            cluster: "cluster".to_string(),
+            entity,
+            window: Window::default(),
+            lanes: None,
+            overlaps: Overlaps::new(false, Arc::new(AtomicU64::new(0))),
+            lifecycles: Lifecycles::default(),
+            matrix: None,
+            topology: None,
+            drives: Drives::default(),
+            order: None,
+            rules: Rules::default(),
+            failures: Failures::default(),
+            tags: None,
+            phases,
+            gaps: Gaps::new(Some(("idle".to_string(), "white".to_string())),
+                None, None).unwrap(),
+            states: Vec::new(),
+        }
+    }
+
+    /* The names of the states drawn, in the order they were drawn. */
+    fn drawn(mut conv: Converter, records: &[TraceData]) -> Vec<String> {
+        let mut sm = conv.statemap();
+        for td in records {
+            conv.record(&mut sm, td).unwrap();
+        }
+
+        let mut out = Vec::new();
+        conv.emit(sm, &mut out).unwrap();
+        let mut lines = out.split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice::<Value>(l).unwrap());
+        lines.next();
+
+        lines.map(|l| l["state"].as_u64().unwrap() as usize)
+            .map(|v| conv.states[v].clone())
+            .collect()
+    }
+
+    fn states(names: &[(i64, &str)]) -> Vec<(DateTime<Utc>, String)> {
+        names.iter().map(|(ms, s)| (at(*ms), s.to_string())).collect()
+    }
+
+    #[test]
+    fn splits_at_first_byte() {
+        let split = split_phases(states(&[(0, "get")]), "get", at(30),
+            "get:processing".to_string(), "get:transfer".to_string());
+        assert_eq!(split, states(&[(0, "get:processing"),
+            (30, "get:transfer")]));
+    }
+
+    #[test]
+    fn splits_around_nested_calls() {
+        /* The first byte falls during a call the request made. */
+        let split = split_phases(
+            states(&[(0, "get"), (10, "read"), (40, "get")]), "get", at(30),
+            "p".to_string(), "t".to_string());
+        assert_eq!(split, states(&[(0, "p"), (10, "read"), (40, "t")]));
+
+        /* It falls between them. */
+        let split = split_phases(
+            states(&[(0, "get"), (10, "read"), (20, "get"), (40, "read")]),
+            "get", at(30), "p".to_string(), "t".to_string());
+        assert_eq!(split, states(&[(0, "p"), (10, "read"), (20, "p"),
+            (30, "t"), (40, "read")]));
+    }
+
+    #[test]
+    fn phases_keep_failed_calls_in_request_view() {
+        let mut read = call("internal.ReadAll", "h2", 100, 200);
+        read.status_code = 503;
+        read.call_stats.time_to_first_byte = 50_000_000;
+        read.request = Some("r".to_string());
+
+        let mut get = call("s3.GetObject", "h1", 0, 400);
+        get.call_stats.time_to_first_byte = 300_000_000;
+        get.request = Some("r".to_string());
+
+        let drawn = drawn(converter(EntityKind::Request, true),
+            &[read, get]);
+        assert_eq!(drawn, vec!["s3.GetObject:processing",
+            "internal.ReadAll [503]", "s3.GetObject:processing",
+            "s3.GetObject:transfer", "idle"]);
+    }
+
+    #[test]
+    fn phases_split_failed_calls() {
+        let mut get = call("s3.GetObject", "h1", 0, 400);
+        get.call_stats.time_to_first_byte = 300_000_000;
+        get.status_code = 503;
+
+        let drawn = drawn(converter(EntityKind::Host, true), &[get]);
+        assert_eq!(drawn, vec!["s3.GetObject:processing [503]",
+            "s3.GetObject:transfer [503]", "idle"]);
+    }
+}
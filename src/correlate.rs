@@ -0,0 +1,366 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+
+use crate::trace::TraceData;
+
+/*
+ * How long an internal call waits for the S3 request that made it before we
+ * give up and leave it uncorrelated. S3 requests are reported when they
+ * finish, after the calls they made, so this needs to be longer than the
+ * slowest request we expect to see.
+ */
+const HORIZON_SECS: i64 = 60;
+
+/*
+ * Correlate works out which S3 request each internal and storage call was
+ * made for, and records it in the call's 'request' field.
+ *
+ * Where both the call and the request carry a request ID (verbose traces from
+ * servers that forward it) they are matched on that. Otherwise a call belongs
+ * to the first S3 request to finish whose interval contains it, on whichever
+ * host it ran. With concurrent requests that is a guess, but for serial
+ * workloads it is exact.
+ *
+ * S3 requests are reported after the calls they made, so calls are queued
+ * until they're claimed or too old to be, and come out in the same order they
+ * went in.
+ */
+pub struct Correlate<I> {
+    records: I,
+    queue: VecDeque<(TraceData, bool)>,
+    /* Sequence number of the record at the front of the queue. */
+    first: u64,
+    /* Queued calls that have a request ID, by ID. */
+    by_id: HashMap<String, Vec<u64>>,
+    latest: Option<DateTime<Utc>>,
+    done: bool,
+}
+
+impl<I> Correlate<I> where I: Iterator<Item = io::Result<TraceData>> {
+    pub fn new(records: I) -> Correlate<I> {
+        Correlate {
+            records,
+            queue: VecDeque::new(),
+            first: 0,
+            by_id: HashMap::new(),
+            latest: None,
+            done: false,
+        }
+    }
+
+    fn add(&mut self, mut td: TraceData) {
+        self.latest = Some(self.latest.map_or(td.time, |l| l.max(td.time)));
+
+        if td.trace_type != "s3" {
+            if let Some(id) = td.request_id() {
+                let seq = self.first + self.queue.len() as u64;
+                self.by_id.entry(id.to_string()).or_default().push(seq);
+            }
+            self.queue.push_back((td, false));
+            return;
+        }
+
+        /*
+         * Name the request by its ID if it has one, since that's unique.
+         * Otherwise its start time, API and path will have to do.
+         */
+        let (begin, end) = (td.start_time(), td.time);
+        let id = td.request_id().map(|id| id.to_string());
+        let key = match &id {
+            Some(id) => format!("{} {} {}", id, td.api, td.path),
+            None => format!("{} {} {}",
+                begin.to_rfc3339_opts(SecondsFormat::Micros, true), td.api,
+                td.path),
+        };
+
+        if let Some(seqs) = id.as_ref().and_then(|id| self.by_id.remove(id)) {
+            for seq in seqs {
+                let (child, claimed) = &mut self.queue[(seq - self.first)
+                    as usize];
+                child.request = Some(key.clone());
+                *claimed = true;
+            }
+        }
+
+        /*
+         * The queue is in end time order, so work back from the newest record
+         * until we reach calls that finished before this request began. A
+         * call that names a different request than this one does was made
+         * for that one, however well the times fit.
+         */
+        for (child, claimed) in self.queue.iter_mut().rev() {
+            if child.time < begin {
+                break;
+            }
+            let other = id.is_some() && child.request_id().is_some();
+            if !*claimed && !other && child.start_time() >= begin &&
+                child.time <= end {
+                child.request = Some(key.clone());
+                *claimed = true;
+            }
+        }
+
+        td.request = Some(key);
+        self.queue.push_back((td, true));
+    }
+
+    /*
+     * Give up on calls that have waited too long for a request to claim them,
+     * or on all of them once the input is exhausted.
+     */
+    fn expire(&mut self) {
+        let cutoff = match self.latest {
+            Some(l) if !self.done =>
+                Some(l - Duration::seconds(HORIZON_SECS)),
+            _ => None,
+        };
+
+        for (td, claimed) in self.queue.iter_mut() {
+            if cutoff.is_some_and(|c| td.time >= c) {
+                break;
+            }
+            *claimed = true;
+        }
+    }
+
+    fn pop(&mut self) -> Option<TraceData> {
+        let (td, _) = self.queue.pop_front()?;
+
+        let first = self.first;
+        if let Some(id) = td.request_id() {
+            if let Some(seqs) = self.by_id.get_mut(id) {
+                seqs.retain(|seq| *seq != first);
+                if seqs.is_empty() {
+                    self.by_id.remove(id);
+                }
+            }
+        }
+        self.first += 1;
+
+        Some(td)
+    }
+}
+
+impl<I> Iterator for Correlate<I>
+    where I: Iterator<Item = io::Result<TraceData>> {
+
+    type Item = io::Result<TraceData>;
+
+    fn next(&mut self) -> Option<io::Result<TraceData>> {
+        loop {
+            if self.queue.front().is_some_and(|(_, claimed)| *claimed) {
+                return self.pop().map(Ok);
+            }
+
+            if self.done {
+                return None;
+            }
+
+            match self.records.next() {
+                Some(Ok(td)) => self.add(td),
+                Some(Err(e)) => return Some(Err(e)),
+                None => self.done = true,
+            }
+
+            self.expire();
+        }
+    }
+}
+
+/*
+ * Lifecycles collects the calls made for each S3 request so that the whole
+ * request can be drawn on a single line of the statemap. Calls made by a
+ * request are nested inside it, so at any moment the line shows the innermost
+ * call in progress: the one that started most recently.
+ */
+struct Call {
+    begin: DateTime<Utc>,
+    end: DateTime<Utc>,
+    state: String,
+}
+
+#[derive(Default)]
+pub struct Lifecycles {
+    calls: HashMap<String, Vec<Call>>,
+}
+
+impl Lifecycles {
+    /*
+     * Add a correlated trace record, drawn in the given state. Once the S3
+     * request itself arrives, return its state changes from the start of the
+     * request, in time order. The request's own calls always arrive before
+     * it does.
+     */
+    pub fn add(&mut self, td: &TraceData, state: String)
+        -> Option<Vec<(DateTime<Utc>, String)>> {
+
+        let key = td.request.as_ref()?;
+        let (begin, end) = (td.start_time(), td.time);
+
+        if td.trace_type != "s3" {
+            self.calls.entry(key.clone()).or_default()
+                .push(Call { begin, end, state });
+            return None;
+        }
+
+        /*
+         * Calls from hosts whose clocks disagree with this one's may appear
+         * to start before or end after the request. Keep them inside it;
+         * any that fall entirely outside it can't be placed and are left out.
+         */
+        let calls: Vec<Call> = self.calls.remove(key).unwrap_or_default()
+            .into_iter()
+            .map(|c| Call {
+                begin: c.begin.max(begin),
+                end: c.end.min(end),
+                state: c.state,
+            })
+            .filter(|c| c.begin < c.end)
+            .collect();
+
+        let mut times: Vec<DateTime<Utc>> = calls.iter()
+            .flat_map(|c| vec![c.begin, c.end])
+            .chain(std::iter::once(begin))
+            .filter(|t| *t < end)
+            .collect();
+        times.sort();
+        times.dedup();
+
+        let mut states: Vec<(DateTime<Utc>, String)> = Vec::new();
+        for t in times {
+            let state = calls.iter()
+                .filter(|c| c.begin <= t && t < c.end)
+                .max_by_key(|c| c.begin)
+                .map_or(&state, |c| &c.state);
+
+            if states.last().is_none_or(|(_, s)| s != state) {
+                states.push((t, state.clone()));
+            }
+        }
+
+        Some(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::{at, call, verbose};
+
+    fn correlate(records: Vec<TraceData>) -> Vec<(String, Option<String>)> {
+        Correlate::new(records.into_iter().map(Ok))
+            .map(|r| r.unwrap())
+            .map(|td| (td.api, td.request))
+            .collect()
+    }
+
+    fn lifecycle(calls: &[(&str, i64, i64)]) -> Vec<(i64, String)> {
+        let mut lifecycles = Lifecycles::default();
+        let mut states = None;
+        for (api, begin, end) in calls {
+            let mut td = call(api, "h1", *begin, *end);
+            td.request = Some("r".to_string());
+            states = lifecycles.add(&td, api.to_string());
+        }
+
+        states.unwrap().into_iter()
+            .map(|(t, s)| ((t - at(0)).num_milliseconds(), s))
+            .collect()
+    }
+
+    #[test]
+    fn claims_calls_within_request() {
+        let records = vec![
+            call("storage.ReadFile", "h2", 5, 10),
+            call("storage.ReadFile", "h2", 15, 30),
+            call("s3.GetObject", "h1", 0, 20),
+        ];
+        let key = Some("2020-04-20T18:00:00.000000Z s3.GetObject \
+            /bucket/object".to_string());
+        assert_eq!(correlate(records), vec![
+            ("storage.ReadFile".to_string(), key.clone()),
+            ("storage.ReadFile".to_string(), None),
+            ("s3.GetObject".to_string(), key),
+        ]);
+    }
+
+    #[test]
+    fn claims_calls_by_request_id() {
+        /*
+         * The first call has an ID of its own in its response, which says
+         * nothing about the request it was made for. The second names
+         * another request, which it's kept for even though the first
+         * request contains it.
+         */
+        let records = vec![
+            verbose(call("internal.ReadAll", "h2", 5, 10), None, Some("X")),
+            verbose(call("internal.ReadAll", "h2", 12, 15), Some("B"),
+                Some("Y")),
+            verbose(call("s3.GetObject", "h1", 0, 20), None, Some("A")),
+            verbose(call("s3.GetObject", "h1", 11, 25), None, Some("B")),
+        ];
+        let (a, b) = ("A s3.GetObject /bucket/object".to_string(),
+            "B s3.GetObject /bucket/object".to_string());
+        assert_eq!(correlate(records), vec![
+            ("internal.ReadAll".to_string(), Some(a.clone())),
+            ("internal.ReadAll".to_string(), Some(b.clone())),
+            ("s3.GetObject".to_string(), Some(a)),
+            ("s3.GetObject".to_string(), Some(b)),
+        ]);
+    }
+
+    #[test]
+    fn names_requests_by_date() {
+        let records = vec![
+            call("s3.GetObject", "h1", 0, 20),
+            call("s3.GetObject", "h1", 86_400_000, 86_400_020),
+        ];
+        let requests: Vec<Option<String>> = correlate(records).into_iter()
+            .map(|(_, r)| r)
+            .collect();
+        assert_ne!(requests[0], requests[1]);
+    }
+
+    #[test]
+    fn shows_innermost_call() {
+        assert_eq!(lifecycle(&[
+            ("storage.ReadFile", 20, 30),
+            ("internal.ReadAll", 10, 40),
+            ("s3.GetObject", 0, 50),
+        ]), vec![
+            (0, "s3.GetObject".to_string()),
+            (10, "internal.ReadAll".to_string()),
+            (20, "storage.ReadFile".to_string()),
+            (30, "internal.ReadAll".to_string()),
+            (40, "s3.GetObject".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn keeps_calls_inside_request() {
+        /*
+         * Calls that seem to stick out of the request are cut down to it,
+         * and those entirely outside it are left out.
+         */
+        assert_eq!(lifecycle(&[
+            ("storage.ReadFile", 0, 20),
+            ("storage.WriteAll", 40, 60),
+            ("storage.StatVol", 60, 70),
+            ("s3.PutObject", 10, 50),
+        ]), vec![
+            (10, "storage.ReadFile".to_string()),
+            (20, "s3.PutObject".to_string()),
+            (40, "storage.WriteAll".to_string()),
+        ]);
+    }
+}
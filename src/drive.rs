@@ -0,0 +1,186 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+
+use crate::trace::TraceData;
+
+/*
+ * MinIO keeps its own metadata in this directory at the top of every drive.
+ */
+const META_DIR: &str = "/.minio.sys/";
+
+/*
+ * Drives works out which drive a storage call touched from its path, e.g.
+ * '/mnt/disk1' for '/mnt/disk1/bucket/object/xl.meta'.
+ *
+ * A path doesn't say where the mount path ends and the bucket begins, so
+ * each server's mount paths are remembered as they're discovered: from the
+ * cluster topology if one was given, and from calls to MinIO's metadata
+ * directory, which always sits at the top of a drive. Until one of a server's
+ * drives is known, the first component of the path is assumed to be the drive.
+ */
+#[derive(Default)]
+pub struct Drives {
+    /* Mount paths on each server, longest first. */
+    mounts: HashMap<String, Vec<String>>,
+}
+
+impl Drives {
+    pub fn add(&mut self, host: &str, mount: &str) {
+        let mount = mount.trim_end_matches('/');
+        if mount.is_empty() {
+            return;
+        }
+
+        let mounts = self.mounts.entry(host.to_string()).or_default();
+        if !mounts.iter().any(|m| m == mount) {
+            mounts.push(mount.to_string());
+            mounts.sort_by_key(|m| std::cmp::Reverse(m.len()));
+        }
+    }
+
+    /*
+     * Look for a drive we haven't seen before in a trace record.
+     */
+    pub fn learn(&mut self, td: &TraceData) {
+        if td.trace_type != "storage" {
+            return;
+        }
+        if let Some(i) = td.path.find(META_DIR) {
+            let mount = td.path[..i].to_string();
+            self.add(&td.host, &mount);
+        }
+    }
+
+    /*
+     * The mount path of the drive a call touched, or None if it didn't
+     * touch one.
+     */
+    pub fn drive(&self, td: &TraceData) -> Option<String> {
+        /*
+         * Older servers' storage REST calls put the drive after the RPC
+         * prefix instead: '/minio/storage/data1/v23/readfile'. The drive
+         * ends at the API version, which a drive's own path may look like
+         * the start of, as in '/minio/storage/mnt/vol1/v23/readfile'.
+         */
+        if let Some(rest) = td.path.strip_prefix("/minio/storage/") {
+            let is_version = |c: &str| c.strip_prefix('v')
+                .is_some_and(|n| !n.is_empty() &&
+                    n.chars().all(|c| c.is_ascii_digit()));
+            let drive: Vec<&str> = rest.split('/')
+                .take_while(|c| !is_version(c))
+                .collect();
+            return Some(format!("/{}", drive.join("/")));
+        }
+
+        if td.trace_type != "storage" {
+            return None;
+        }
+
+        let mounts = self.mounts.get(&td.host);
+        if let Some(mount) = mounts
+            .and_then(|mounts| mounts.iter().find(|m| is_under(&td.path, m)))
+        {
+            return Some(mount.clone());
+        }
+
+        /*
+         * A drive we haven't seen yet is probably mounted at the same depth
+         * as the server's other drives.
+         */
+        let depth = mounts
+            .and_then(|mounts| mounts.last())
+            .map_or(1, |m| m.trim_start_matches('/').split('/').count());
+
+        let components: Vec<&str> = td.path.trim_start_matches('/')
+            .splitn(depth + 1, '/')
+            .take(depth)
+            .collect();
+        if components.len() < depth || components.iter().any(|c| c.is_empty())
+        {
+            return None;
+        }
+
+        Some(format!("/{}", components.join("/")))
+    }
+}
+
+/* Is 'path' at or below the directory 'dir'? */
+fn is_under(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::call;
+
+    fn storage(host: &str, path: &str) -> TraceData {
+        let mut td = call("storage.ReadAll", host, 0, 1);
+        td.path = path.to_string();
+        td
+    }
+
+    #[test]
+    fn guesses_first_component() {
+        let drives = Drives::default();
+        assert_eq!(drives.drive(&storage("h1", "/data1/bucket/object")),
+            Some("/data1".to_string()));
+        assert_eq!(drives.drive(&storage("h1", "/")), None);
+        assert_eq!(drives.drive(&call("s3.GetObject", "h1", 0, 1)), None);
+    }
+
+    #[test]
+    fn learns_from_metadata_calls() {
+        let mut drives = Drives::default();
+        drives.learn(&storage("h1", "/mnt/disk1/.minio.sys/format.json"));
+
+        assert_eq!(drives.drive(&storage("h1", "/mnt/disk1/b/o/xl.meta")),
+            Some("/mnt/disk1".to_string()));
+
+        /* Other drives on the server are assumed to be as deep. */
+        assert_eq!(drives.drive(&storage("h1", "/mnt/disk2/b/o/xl.meta")),
+            Some("/mnt/disk2".to_string()));
+        assert_eq!(drives.drive(&storage("h1", "/mnt")), None);
+
+        /* But not on other servers. */
+        assert_eq!(drives.drive(&storage("h2", "/mnt/disk2/b/o/xl.meta")),
+            Some("/mnt".to_string()));
+    }
+
+    #[test]
+    fn prefers_longest_mount() {
+        let mut drives = Drives::default();
+        drives.add("h1", "/data/");
+        drives.add("h1", "/data/ssd1");
+        drives.add("h1", "/data/ssd1");
+
+        assert_eq!(drives.drive(&storage("h1", "/data/ssd1/b/o")),
+            Some("/data/ssd1".to_string()));
+        assert_eq!(drives.drive(&storage("h1", "/data/ssd10/b/o")),
+            Some("/data".to_string()));
+    }
+
+    #[test]
+    fn reads_storage_rpc_paths() {
+        let drives = Drives::default();
+        let rpc = |path: &str| {
+            let mut td = call("internal.ReadAll", "h1", 0, 1);
+            td.path = path.to_string();
+            drives.drive(&td)
+        };
+
+        assert_eq!(rpc("/minio/storage/data1/v23/readall"),
+            Some("/data1".to_string()));
+        assert_eq!(rpc("/minio/storage/mnt/vol1/v23/readall"),
+            Some("/mnt/vol1".to_string()));
+        assert_eq!(rpc("/minio/storage/data1"), Some("/data1".to_string()));
+    }
+}
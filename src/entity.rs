@@ -0,0 +1,182 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::str::FromStr;
+
+use crate::drive::Drives;
+use crate::links;
+use crate::trace::TraceData;
+
+/*
+ * What each line (entity) in the statemap represents.
+ */
+#[derive(Clone, Copy)]
+pub enum EntityKind {
+    /* The MinIO server that handled the call. */
+    Host,
+    /* The caller: an S3 client, or another MinIO server for internal calls. */
+    Client,
+    /* The bucket named in the call's path. */
+    Bucket,
+    /* The API called, e.g. 's3.PutObject'. */
+    Api,
+    /* The server and the drive on it that the call touched. */
+    Drive,
+    /* The S3 request a call was made for (see correlate.rs). */
+    Request,
+    /* The caller and the server it called, e.g. 'minio1 -> minio2'. */
+    Link,
+    /*
+     * The object an S3 call named, or with a depth, the prefix of its key
+     * with that many components, e.g. 'bucket/logs/' for a depth of 1.
+     */
+    Object(Option<usize>),
+}
+
+impl FromStr for EntityKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EntityKind, String> {
+        match s {
+            "host" => Ok(EntityKind::Host),
+            "client" => Ok(EntityKind::Client),
+            "bucket" => Ok(EntityKind::Bucket),
+            "api" => Ok(EntityKind::Api),
+            "drive" => Ok(EntityKind::Drive),
+            "request" => Ok(EntityKind::Request),
+            "link" => Ok(EntityKind::Link),
+            "object" => Ok(EntityKind::Object(None)),
+            _ => match s.strip_prefix("prefix:").map(|n| n.parse()) {
+                Some(Ok(depth)) => Ok(EntityKind::Object(Some(depth))),
+                _ => Err(format!("unknown entity type '{}': expected 'host', \
+                    'client', 'bucket', 'api', 'drive', 'request', 'link', \
+                    'object' or 'prefix:N'", s)),
+            },
+        }
+    }
+}
+
+impl EntityKind {
+    /* How the entities are described in the statemap legend. */
+    pub fn description(self) -> &'static str {
+        match self {
+            EntityKind::Host => "Host",
+            EntityKind::Client => "Client",
+            EntityKind::Bucket => "Bucket",
+            EntityKind::Api => "API",
+            EntityKind::Drive => "Drive",
+            EntityKind::Request => "Request",
+            EntityKind::Link => "Link",
+            EntityKind::Object(None) => "Object",
+            EntityKind::Object(Some(_)) => "Prefix",
+        }
+    }
+
+    /*
+     * The group an entity naturally belongs to, if any: the server a drive
+     * is on, the caller of a link or the bucket an object is in.
+     */
+    pub fn group(self, td: &TraceData) -> Option<String> {
+        match self {
+            EntityKind::Drive => Some(td.host.clone()),
+            EntityKind::Link if td.client.is_empty() =>
+                Some("unknown".to_string()),
+            EntityKind::Link => Some(td.client.clone()),
+            EntityKind::Object(_) => object(td, Some(0))
+                .map(|b| b.trim_end_matches('/').to_string()),
+            _ => None,
+        }
+    }
+
+    /*
+     * Whether a trace record is drawn at all. The object view only shows the
+     * S3 calls that name an object, not those on a whole bucket or the
+     * internal calls they made.
+     */
+    pub fn includes(self, td: &TraceData) -> bool {
+        match self {
+            EntityKind::Object(_) =>
+                td.trace_type == "s3" && object(td, None).is_some(),
+            _ => true,
+        }
+    }
+
+    /*
+     * The entity a trace record belongs to. Records that don't name a bucket
+     * or drive fall back to something sensible rather than being lost.
+     */
+    pub fn entity(self, td: &TraceData, drives: &Drives) -> String {
+        match self {
+            EntityKind::Host => td.host.clone(),
+            EntityKind::Client if td.client.is_empty() => "unknown".to_string(),
+            EntityKind::Client => td.client.clone(),
+            EntityKind::Bucket => match bucket(td, drives) {
+                Some(b) => b.to_string(),
+                None => "(no bucket)".to_string(),
+            },
+            EntityKind::Api => td.api.clone(),
+            EntityKind::Drive => match drives.drive(td) {
+                Some(d) => format!("{}{}", td.host, d),
+                None => td.host.clone(),
+            },
+            EntityKind::Request => match &td.request {
+                Some(r) => r.clone(),
+                None => "(uncorrelated)".to_string(),
+            },
+            EntityKind::Link => links::link(td),
+            EntityKind::Object(depth) => match object(td, depth) {
+                Some(o) => o,
+                None => "(no object)".to_string(),
+            },
+        }
+    }
+}
+
+/*
+ * S3 paths are '/bucket/object...', so the bucket is the first component.
+ * Storage paths have the drive in front of that.
+ */
+fn bucket<'a>(td: &'a TraceData, drives: &Drives) -> Option<&'a str> {
+    let path = match drives.drive(td) {
+        Some(d) if td.trace_type == "storage" =>
+            td.path.strip_prefix(d.as_str()).unwrap_or(&td.path),
+        _ => &td.path,
+    };
+
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        .filter(|b| !b.is_empty())
+}
+
+/*
+ * The bucket and key an S3 call named, cut down to the first 'depth'
+ * components of the key if a depth is given. Prefixes end in '/' so they
+ * can't be mistaken for an object of the same name.
+ */
+fn object(td: &TraceData, depth: Option<usize>) -> Option<String> {
+    let mut parts = td.path.trim_start_matches('/').splitn(2, '/');
+    let bucket = parts.next().filter(|b| !b.is_empty())?;
+    let key = parts.next().filter(|k| !k.is_empty())?;
+
+    let depth = match depth {
+        Some(d) => d,
+        None => return Some(format!("{}/{}", bucket, key)),
+    };
+
+    let components: Vec<&str> = key.split('/').collect();
+    if components.len() <= depth {
+        return Some(format!("{}/{}", bucket, key));
+    }
+
+    if depth == 0 {
+        return Some(format!("{}/", bucket));
+    }
+
+    Some(format!("{}/{}/", bucket, components[..depth].join("/")))
+}
@@ -0,0 +1,96 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use crate::order::natural_cmp;
+use crate::trace::TraceData;
+
+/* Colors for the states of failed calls. */
+const CLIENT_ERROR_COLOR: &str = "orange";
+const SERVER_ERROR_COLOR: &str = "red";
+
+/*
+ * Failures makes failed calls stand out. Each is drawn in a state of its own,
+ * named after the call's usual state and what went wrong, e.g.
+ * 's3.PutObject [503]' or 'storage.ReadFile [error]', and colored as a
+ * warning. Failures are also counted for each entity, for a summary at the
+ * end.
+ */
+#[derive(Default)]
+pub struct Failures {
+    /* The states failed calls have been drawn in, and their colors. */
+    states: HashMap<String, &'static str>,
+    /* entity => failure => count */
+    counts: HashMap<String, BTreeMap<String, u64>>,
+}
+
+impl Failures {
+    /*
+     * The state to draw a call in, given the state it would be drawn in if
+     * it had succeeded.
+     */
+    pub fn state(&mut self, td: &TraceData, state: String) -> String {
+        let failure = match td.failure() {
+            Some(f) => f,
+            None => return state,
+        };
+
+        let color = if (400..500).contains(&td.status_code) {
+            CLIENT_ERROR_COLOR
+        } else {
+            SERVER_ERROR_COLOR
+        };
+
+        let state = format!("{} [{}]", state, failure);
+        self.states.entry(state.clone()).or_insert(color);
+        state
+    }
+
+    pub fn count(&mut self, entity: &str, td: &TraceData) {
+        if let Some(failure) = td.failure() {
+            *self.counts.entry(entity.to_string()).or_default()
+                .entry(failure).or_default() += 1;
+        }
+    }
+
+    pub fn colors(&self) -> impl Iterator<Item = (&String, &&'static str)> {
+        self.states.iter()
+    }
+
+    /*
+     * Print the number of failed calls on each entity that had any, most
+     * first, broken down by what went wrong.
+     */
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.counts.is_empty() {
+            return Ok(());
+        }
+
+        let mut entities: Vec<(&String, u64)> = self.counts.iter()
+            .map(|(e, c)| (e, c.values().sum()))
+            .collect();
+        entities.sort_by(|(ea, na), (eb, nb)| {
+            nb.cmp(na).then_with(|| natural_cmp(ea, eb))
+        });
+
+        let width = entities.iter().map(|(e, _)| e.len()).max().unwrap_or(0);
+
+        writeln!(out, "failed calls by entity:")?;
+        for (entity, total) in entities {
+            let detail: Vec<String> = self.counts[entity].iter()
+                .map(|(f, n)| format!("{}: {}", f, n))
+                .collect();
+            writeln!(out, "{:<w$}  {:>6}  ({})", entity, total,
+                detail.join(", "), w = width)?;
+        }
+
+        Ok(())
+    }
+}
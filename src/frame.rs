@@ -0,0 +1,231 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::io::{self, BufRead};
+
+/*
+ * A Frame is the raw text of one top-level JSON value from a trace input,
+ * along with where it was found. The text is not guaranteed to be valid JSON.
+ */
+pub struct Frame {
+    pub line: u64,
+    pub offset: u64,
+    pub text: Vec<u8>,
+}
+
+/*
+ * Framer splits a trace input into Frames without parsing them.
+ *
+ * serde_json's stream deserializer gives up at the first syntax error, but
+ * traces commonly end with (or, when captures are concatenated, contain) a
+ * record that was cut short when `mc` was killed. Splitting the input
+ * ourselves lets a bad record be skipped without losing the rest.
+ *
+ * `mc` writes each record starting at the beginning of a line, and indents
+ * anything nested, so an opening brace in the first column while a record is
+ * still open means that record was truncated. Likewise a raw newline can't
+ * appear inside a JSON string, so one ends any string we thought we were in.
+ */
+pub struct Framer<R: BufRead> {
+    input: R,
+    line: u64,
+    offset: u64,
+    column: u64,
+}
+
+impl<R: BufRead> Framer<R> {
+    pub fn new(input: R) -> Framer<R> {
+        Framer {
+            input,
+            line: 1,
+            offset: 0,
+            column: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Framer<R> {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<io::Result<Frame>> {
+        let mut frame = Frame { line: 0, offset: 0, text: Vec::new() };
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut junk = false;
+
+        loop {
+            let buf = match self.input.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if buf.is_empty() {
+                /* EOF, possibly part way through a record. */
+                if frame.text.is_empty() {
+                    return None;
+                }
+                return Some(Ok(frame));
+            }
+
+            let mut used = 0;
+            let mut done = false;
+
+            for &b in buf {
+                if depth > 0 && b == b'{' && self.column == 0 {
+                    /*
+                     * The start of the next record. Leave it in the buffer
+                     * and hand back the truncated one.
+                     */
+                    done = true;
+                    break;
+                }
+
+                let (line, offset) = (self.line, self.offset);
+                used += 1;
+                self.offset += 1;
+                if b == b'\n' {
+                    self.line += 1;
+                    self.column = 0;
+                } else {
+                    self.column += 1;
+                }
+
+                if frame.text.is_empty() {
+                    if b.is_ascii_whitespace() {
+                        continue;
+                    }
+                    frame.line = line;
+                    frame.offset = offset;
+                    if b == b'{' || b == b'[' {
+                        depth = 1;
+                    } else {
+                        /* Not a JSON object; take the rest of the line. */
+                        junk = true;
+                    }
+                    frame.text.push(b);
+                    continue;
+                }
+
+                if junk {
+                    if b == b'\n' {
+                        done = true;
+                        break;
+                    }
+                    frame.text.push(b);
+                    continue;
+                }
+
+                frame.text.push(b);
+
+                if in_string {
+                    if escaped {
+                        escaped = false;
+                    } else if b == b'\\' {
+                        escaped = true;
+                    } else if b == b'"' || b == b'\n' {
+                        in_string = false;
+                    }
+                    continue;
+                }
+
+                match b {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            done = true;
+                            break;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+
+            self.input.consume(used);
+
+            if done {
+                return Some(Ok(frame));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(input: &str) -> Vec<(u64, u64, String)> {
+        Framer::new(input.as_bytes())
+            .map(|f| {
+                let f = f.unwrap();
+                (f.line, f.offset, String::from_utf8(f.text).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_records() {
+        let input = "{\"a\": 1}\n{\"b\": {\n  \"c\": [2]\n}}\n";
+        assert_eq!(frames(input), vec![
+            (1, 0, "{\"a\": 1}".to_string()),
+            (2, 9, "{\"b\": {\n  \"c\": [2]\n}}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn truncated_at_end() {
+        let input = "{\"a\": 1}\n{\"b\": \"cut";
+        assert_eq!(frames(input), vec![
+            (1, 0, "{\"a\": 1}".to_string()),
+            (2, 9, "{\"b\": \"cut".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn truncated_then_newline() {
+        let input = "{\"a\": {\"b\": \"cut\n{\"c\": 1}\n";
+        assert_eq!(frames(input), vec![
+            (1, 0, "{\"a\": {\"b\": \"cut\n".to_string()),
+            (2, 17, "{\"c\": 1}".to_string()),
+        ]);
+    }
+
+    /*
+     * The framer can't split these; Records recovers the second record (see
+     * trace.rs).
+     */
+    #[test]
+    fn truncated_then_same_line() {
+        let input = "{\"a\": \"cut{\"c\": 1}\n{\"d\": 2}\n";
+        assert_eq!(frames(input), vec![
+            (1, 0, "{\"a\": \"cut{\"c\": 1}\n".to_string()),
+            (2, 19, "{\"d\": 2}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn junk_lines() {
+        let input = "mc: <ERROR> Unable to trace\n{\"a\": 1}\n";
+        assert_eq!(frames(input), vec![
+            (1, 0, "mc: <ERROR> Unable to trace".to_string()),
+            (2, 28, "{\"a\": 1}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let input = "{\"a\": \"say \\\"}\\\" {\"}\n{\"b\": \"\\\\\"}\n";
+        assert_eq!(frames(input), vec![
+            (1, 0, "{\"a\": \"say \\\"}\\\" {\"}".to_string()),
+            (2, 21, "{\"b\": \"\\\\\"}".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, Utc};
+use statemap::Statemap;
+
+/* The states for gaps that are too short or too long to be idleness. */
+const BETWEEN_OPS: &str = "between ops";
+const BETWEEN_OPS_COLOR: &str = "whitesmoke";
+const NO_DATA: &str = "no data";
+const NO_DATA_COLOR: &str = "lightgray";
+
+/*
+ * Gaps decides what state an entity is drawn in between calls.
+ *
+ * By default every gap is the idle state, but a gap of a few microseconds
+ * between back-to-back calls means something different from tens of seconds
+ * of nothing, which on a busy cluster more likely means trace records went
+ * missing. So gaps shorter than one threshold can be drawn as 'between ops'
+ * and gaps longer than another as 'no data'. With the idle state suppressed,
+ * gaps that would have been idle are drawn as 'between ops' instead.
+ *
+ * A gap can't be classified until the entity's next call arrives, so each
+ * entity's last call is remembered until then. Gaps still open when a
+ * statemap is printed run to its end and are drawn as idle. In follow mode
+ * they stay open, and once they close the next statemap picks them up from
+ * where the last one ended, drawn in the state their full length calls for.
+ */
+pub struct Gaps {
+    /* The idle state, unless it has been suppressed, and its color. */
+    idle: Option<(String, String)>,
+    between_ops: Option<Duration>,
+    no_data: Option<Duration>,
+    /* When each entity last went quiet, if it hasn't been busy since. */
+    open: HashMap<String, DateTime<Utc>>,
+    /* The latest any entity went quiet. */
+    latest: Option<DateTime<Utc>>,
+    /* Where the last statemap printed ended, if one has been. */
+    printed: Option<DateTime<Utc>>,
+    /* The gap states that have been drawn, and their colors. */
+    used: BTreeMap<String, String>,
+}
+
+impl Gaps {
+    pub fn new(idle: Option<(String, String)>, between_ops: Option<Duration>,
+        no_data: Option<Duration>) -> Result<Gaps, String> {
+
+        if let (Some(b), Some(n)) = (between_ops, no_data) {
+            if b > n {
+                return Err("the 'between ops' threshold must not be longer \
+                    than the 'no data' threshold".to_string());
+            }
+        }
+
+        Ok(Gaps {
+            idle,
+            between_ops,
+            no_data,
+            open: HashMap::new(),
+            latest: None,
+            printed: None,
+            used: BTreeMap::new(),
+        })
+    }
+
+    /* The state a gap of the given length is drawn in. */
+    fn state(&mut self, gap: Option<Duration>) -> String {
+        let (state, color) = match gap {
+            Some(g) if self.between_ops.is_some_and(|b| g < b) =>
+                (BETWEEN_OPS, BETWEEN_OPS_COLOR),
+            Some(g) if self.no_data.is_some_and(|n| g > n) =>
+                (NO_DATA, NO_DATA_COLOR),
+            _ => match &self.idle {
+                Some((state, color)) => (state.as_str(), color.as_str()),
+                None => (BETWEEN_OPS, BETWEEN_OPS_COLOR),
+            },
+        };
+
+        self.used.entry(state.to_string())
+            .or_insert_with(|| color.to_string());
+        state.to_string()
+    }
+
+    /*
+     * An entity has become busy at 'begin'. Close the gap since it was last
+     * busy, if there was one.
+     */
+    pub fn close(&mut self, sm: &mut Statemap, entity: &str,
+        begin: DateTime<Utc>) {
+
+        if let Some(quiet) = self.open.remove(entity) {
+            let from = self.from(quiet);
+            if from < begin {
+                let state = self.state(Some(begin - quiet));
+                sm.set_state(entity, &state, None, from);
+            }
+        }
+    }
+
+    /*
+     * An entity has gone quiet at 'end'. If it was already quiet from
+     * later on, it was still busy with another call until then.
+     */
+    pub fn open(&mut self, entity: &str, end: DateTime<Utc>) {
+        let quiet = self.open.entry(entity.to_string()).or_insert(end);
+        *quiet = (*quiet).max(end);
+        self.latest = self.latest.max(Some(end));
+    }
+
+    /*
+     * Where to start drawing a gap in the statemap being made: where it
+     * began, unless that was covered by a statemap already printed.
+     */
+    fn from(&self, quiet: DateTime<Utc>) -> DateTime<Utc> {
+        self.printed.map_or(quiet, |p| quiet.max(p))
+    }
+
+    /*
+     * Draw the gaps that opened in a statemap that's about to be printed,
+     * as idle. They stay open, but later statemaps only draw them once
+     * they close.
+     */
+    pub fn print(&mut self, sm: &mut Statemap) {
+        let printed = self.printed;
+        let opened: Vec<(String, DateTime<Utc>)> = self.open.iter()
+            .filter(|(_, q)| printed.is_none_or(|p| **q > p))
+            .map(|(e, q)| (e.clone(), *q))
+            .collect();
+        for (entity, quiet) in opened {
+            let state = self.state(None);
+            sm.set_state(&entity, &state, None, quiet);
+        }
+        self.printed = self.latest;
+    }
+
+    pub fn colors(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.used.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::trace::testing::at;
+
+    fn gaps(between_ops: Option<i64>, no_data: Option<i64>) -> Gaps {
+        Gaps::new(Some(("idle".to_string(), "white".to_string())),
+            between_ops.map(Duration::milliseconds),
+            no_data.map(Duration::milliseconds)).unwrap()
+    }
+
+    fn statemap() -> Statemap {
+        Statemap::new("test", None, None)
+    }
+
+    /* The states drawn, as entity, state and milliseconds after at(0). */
+    fn drawn(sm: Statemap) -> Vec<(String, String, i64)> {
+        let mut records = sm.into_iter()
+            .map(|r| serde_json::from_str::<Value>(&r).unwrap());
+        let metadata = match records.next() {
+            Some(m) => m,
+            None => return Vec::new(),
+        };
+
+        let start = metadata["start"][0].as_i64().unwrap() * 1_000_000_000 +
+            metadata["start"][1].as_i64().unwrap();
+        let origin = at(0).timestamp_nanos();
+        let names = metadata["states"].as_object().unwrap();
+
+        records.map(|r| {
+            let value = r["state"].as_u64().unwrap();
+            let state = names.iter()
+                .find(|(_, d)| d["value"].as_u64() == Some(value))
+                .map(|(s, _)| s.clone())
+                .unwrap();
+            let ns: i64 = r["time"].as_str().unwrap().parse().unwrap();
+            (r["entity"].as_str().unwrap().to_string(), state,
+                (start + ns - origin) / 1_000_000)
+        }).collect()
+    }
+
+    fn state(entity: &str, state: &str, ms: i64) -> (String, String, i64) {
+        (entity.to_string(), state.to_string(), ms)
+    }
+
+    #[test]
+    fn classifies_gaps_by_length() {
+        let mut gaps = gaps(Some(10), Some(100));
+        let mut sm = statemap();
+
+        for (quiet, busy) in &[(0, 9), (20, 30), (40, 140), (150, 251)] {
+            gaps.open("e", at(*quiet));
+            gaps.close(&mut sm, "e", at(*busy));
+        }
+
+        /* Gaps as long as either threshold are idle. */
+        assert_eq!(drawn(sm), vec![
+            state("e", BETWEEN_OPS, 0),
+            state("e", "idle", 20),
+            state("e", "idle", 40),
+            state("e", NO_DATA, 150),
+        ]);
+        assert_eq!(gaps.colors().count(), 3);
+    }
+
+    #[test]
+    fn draws_idle_as_between_ops_without_idle_state() {
+        let mut gaps = Gaps::new(None, None, Some(Duration::milliseconds(100)))
+            .unwrap();
+        let mut sm = statemap();
+
+        gaps.open("e", at(0));
+        gaps.close(&mut sm, "e", at(50));
+        gaps.open("e", at(60));
+        gaps.close(&mut sm, "e", at(200));
+
+        assert_eq!(drawn(sm), vec![
+            state("e", BETWEEN_OPS, 0),
+            state("e", NO_DATA, 60),
+        ]);
+    }
+
+    #[test]
+    fn ignores_gap_while_busy() {
+        /* A call that ends while a longer one is still going. */
+        let mut gaps = gaps(None, None);
+        let mut sm = statemap();
+
+        gaps.open("e", at(20));
+        gaps.open("e", at(10));
+        gaps.close(&mut sm, "e", at(15));
+
+        assert!(drawn(sm).is_empty());
+    }
+
+    #[test]
+    fn rejects_overlapping_thresholds() {
+        let err = Gaps::new(None, Some(Duration::seconds(2)),
+            Some(Duration::seconds(1)));
+        assert!(err.is_err());
+        assert!(Gaps::new(None, Some(Duration::seconds(1)),
+            Some(Duration::seconds(1))).is_ok());
+    }
+
+    #[test]
+    fn carries_gaps_across_statemaps() {
+        let mut gaps = gaps(Some(20), Some(100));
+
+        /* Both entities are quiet when the first statemap is printed. */
+        let mut sm = statemap();
+        gaps.open("e", at(0));
+        gaps.open("f", at(50));
+        gaps.print(&mut sm);
+        assert_eq!(drawn(sm), vec![state("e", "idle", 0),
+            state("f", "idle", 50)]);
+
+        /*
+         * The next statemap picks them up where the first one ended, in the
+         * state their whole length calls for. Gaps still open aren't drawn
+         * again.
+         */
+        let mut sm = statemap();
+        gaps.close(&mut sm, "e", at(500));
+        gaps.open("e", at(600));
+        gaps.print(&mut sm);
+        assert_eq!(drawn(sm), vec![state("e", NO_DATA, 50),
+            state("e", "idle", 600)]);
+
+        let mut sm = statemap();
+        gaps.close(&mut sm, "f", at(610));
+        assert_eq!(drawn(sm), vec![state("f", NO_DATA, 600)]);
+    }
+}
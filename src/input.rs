@@ -0,0 +1,300 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+/* Enough bytes to identify any of the supported compression formats. */
+const MAGIC_LEN: usize = 6;
+
+/* How often to check a followed file for new data. */
+const FOLLOW_POLL: Duration = Duration::from_millis(250);
+
+/*
+ * Expand the list of '-i' arguments into the individual inputs to read.
+ * Each argument may be a file, a directory (every file within it is read), a
+ * glob pattern, or '-' for stdin. With no arguments at all we read stdin.
+ */
+pub fn expand_inputs(args: &[String]) -> io::Result<Vec<String>> {
+    if args.is_empty() {
+        return Ok(vec!["-".to_string()]);
+    }
+
+    let mut inputs = Vec::new();
+
+    for arg in args {
+        if arg == "-" {
+            inputs.push(arg.clone());
+        } else if Path::new(arg).is_dir() {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(arg)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    files.push(path.to_string_lossy().into_owned());
+                }
+            }
+            files.sort();
+            inputs.extend(files);
+        } else if arg.contains(&['*', '?', '['][..]) {
+            let paths = glob::glob(arg).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("invalid input pattern '{}': {}", arg, e))
+            })?;
+
+            let mut matched = false;
+            for path in paths {
+                let path = path.map_err(io::Error::from)?;
+                inputs.push(path.to_string_lossy().into_owned());
+                matched = true;
+            }
+
+            if !matched {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                    format!("no input files match '{}'", arg)));
+            }
+        } else {
+            inputs.push(arg.clone());
+        }
+    }
+
+    if inputs.iter().filter(|i| *i == "-").count() > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "stdin may only be given as an input once"));
+    }
+
+    Ok(inputs)
+}
+
+/*
+ * Open a trace input. A filename of '-' means stdin, which lets users pipe
+ * `mc admin trace` directly into minio-statemap.
+ *
+ * Compressed inputs are detected by their magic bytes rather than by file
+ * extension so that compressed data arriving on stdin is handled too.
+ *
+ * If 'follow' is set, files are read like `tail -F` and never reach EOF.
+ * Stdin needs no special treatment; it ends when the writer goes away.
+ */
+pub fn open_input(filename: &str, follow: bool)
+    -> io::Result<Box<dyn Read + Send>> {
+
+    let raw: Box<dyn Read + Send> = match filename {
+        "-" => Box::new(io::stdin()),
+        f if follow => Box::new(Follow::open(f)?),
+        f => Box::new(File::open(f)?),
+    };
+
+    Ok(Box::new(Decompress { raw: Some(raw), stream: None }))
+}
+
+/*
+ * Decompress works out whether its input is compressed when it's first read
+ * rather than when it's opened: the start of a followed file or a pipe may be
+ * a long time coming, and other inputs shouldn't wait for it.
+ */
+struct Decompress {
+    raw: Option<Box<dyn Read + Send>>,
+    stream: Option<Box<dyn Read + Send>>,
+}
+
+impl Read for Decompress {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(raw) = self.raw.take() {
+            self.stream = Some(decompress(raw)?);
+        }
+
+        match &mut self.stream {
+            Some(s) => s.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+/*
+ * Sniff the first few bytes of the input and wrap it in the matching
+ * decoder. The sniffed bytes are stitched back onto the front of the stream
+ * so that nothing is lost for uncompressed input.
+ */
+fn decompress(mut raw: Box<dyn Read + Send>)
+    -> io::Result<Box<dyn Read + Send>> {
+
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+
+    /*
+     * A single read() on a pipe may return fewer bytes than we asked for, so
+     * keep reading until we either have enough to compare or hit EOF.
+     */
+    (&mut raw).take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+
+    let stream = Cursor::new(magic.clone()).chain(raw);
+
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(stream)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::stream::read::Decoder::new(stream)?))
+    } else if magic.starts_with(XZ_MAGIC) {
+        Ok(Box::new(XzDecoder::new_multi_decoder(stream)))
+    } else {
+        Ok(Box::new(stream))
+    }
+}
+
+/*
+ * Follow reads a file that is still being written to. Rather than returning
+ * EOF it waits for more data to arrive.
+ *
+ * As with `tail -F`, if the file is truncated we start again from the
+ * beginning, and if it is replaced (e.g. by log rotation) we finish reading
+ * the old file and then switch to the new one.
+ */
+struct Follow {
+    path: PathBuf,
+    file: File,
+    pos: u64,
+}
+
+impl Follow {
+    fn open(path: &str) -> io::Result<Follow> {
+        Ok(Follow {
+            path: PathBuf::from(path),
+            file: File::open(path)?,
+            pos: 0,
+        })
+    }
+
+    /*
+     * Called when we've read everything currently in the file. Reopen or
+     * rewind it if it has been rotated or truncated.
+     */
+    fn check(&mut self) -> io::Result<()> {
+        let current = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            /* Rotated away and not yet replaced. */
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if !same_file(&current, &self.file.metadata()?) {
+            self.file = File::open(&self.path)?;
+            self.pos = 0;
+        } else if current.len() < self.pos {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.pos = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+
+            thread::sleep(FOLLOW_POLL);
+            self.check()?;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/* Without inode numbers we can only notice truncation, not replacement. */
+#[cfg(not(unix))]
+fn same_file(_a: &Metadata, _b: &Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+
+    use super::*;
+
+    const TRACE: &str = concat!(r#"{"api": "s3.GetObject"}"#, "\n",
+        r#"{"api": "s3.PutObject"}"#, "\n");
+
+    /* A pipe that hands over a byte at a time. */
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    fn read(data: Vec<u8>) -> String {
+        let mut text = String::new();
+        decompress(Box::new(Trickle(Cursor::new(data)))).unwrap()
+            .read_to_string(&mut text).unwrap();
+        text
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(text.as_bytes()).unwrap();
+        gz.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_gzip() {
+        assert_eq!(read(gzip(TRACE)), TRACE);
+
+        /* Files that were appended to are several gzip streams in one. */
+        let mut data = gzip(TRACE);
+        data.extend(gzip(TRACE));
+        assert_eq!(read(data), TRACE.repeat(2));
+    }
+
+    #[test]
+    fn reads_zstd() {
+        let data = zstd::stream::encode_all(TRACE.as_bytes(), 0).unwrap();
+        assert_eq!(read(data), TRACE);
+    }
+
+    #[test]
+    fn reads_xz() {
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(TRACE.as_bytes()).unwrap();
+        assert_eq!(read(xz.finish().unwrap()), TRACE);
+    }
+
+    #[test]
+    fn reads_plain() {
+        assert_eq!(read(TRACE.as_bytes().to_vec()), TRACE);
+
+        /* Shorter than any magic number, and one that starts like one. */
+        assert_eq!(read(b"{}".to_vec()), "{}");
+        assert_eq!(read(vec![0x1f]), "\u{1f}");
+        assert_eq!(read(Vec::new()), "");
+    }
+}
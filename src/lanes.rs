@@ -0,0 +1,104 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+/*
+ * An entity in a statemap can only be in one state at a time, but a MinIO
+ * server under concurrent load is working on many requests at once. Lanes
+ * splits each entity into as many virtual entities ('node1/0', 'node1/1',
+ * ...) as it needs so that no two overlapping operations share one.
+ *
+ * Operations are packed greedily. Because trace records arrive in end time
+ * order, a lane is idle for an operation exactly when the last operation put
+ * in it ended before this one began. Of the idle lanes, each operation goes in
+ * the one that became idle last, leaving those idle for longer to operations
+ * that began earlier. That uses no more lanes than the most operations in
+ * progress at once; taking the lowest-numbered idle lane can use more.
+ */
+#[derive(Default)]
+pub struct Lanes {
+    /* When the last operation in each of an entity's lanes ended. */
+    busy_until: HashMap<String, Vec<DateTime<Utc>>>,
+}
+
+impl Lanes {
+    /*
+     * Choose a lane for an operation on 'entity' running from 'begin' to
+     * 'end', and return the name of the lane's entity.
+     */
+    pub fn assign(&mut self, entity: &str, begin: DateTime<Utc>,
+        end: DateTime<Utc>) -> String {
+
+        let lanes = self.busy_until.entry(entity.to_string())
+            .or_default();
+
+        let idle = lanes.iter().enumerate()
+            .filter(|(_, until)| **until <= begin)
+            .min_by_key(|(_, until)| begin - **until)
+            .map(|(i, _)| i);
+
+        let lane = match idle {
+            Some(i) => {
+                lanes[i] = end;
+                i
+            },
+            None => {
+                lanes.push(end);
+                lanes.len() - 1
+            },
+        };
+
+        format!("{}/{}", entity, lane)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::at;
+
+    #[test]
+    fn splits_overlapping_operations() {
+        let mut lanes = Lanes::default();
+        assert_eq!(lanes.assign("h1", at(0), at(10)), "h1/0");
+        assert_eq!(lanes.assign("h1", at(5), at(20)), "h1/1");
+        assert_eq!(lanes.assign("h1", at(8), at(25)), "h1/2");
+        assert_eq!(lanes.assign("h2", at(8), at(25)), "h2/0");
+    }
+
+    #[test]
+    fn reuses_latest_idle_lane() {
+        let mut lanes = Lanes::default();
+        assert_eq!(lanes.assign("h1", at(0), at(10)), "h1/0");
+        assert_eq!(lanes.assign("h1", at(0), at(20)), "h1/1");
+        assert_eq!(lanes.assign("h1", at(0), at(30)), "h1/2");
+
+        /* Back to back with the first operation, so it can share its lane. */
+        assert_eq!(lanes.assign("h1", at(10), at(40)), "h1/0");
+        assert_eq!(lanes.assign("h1", at(35), at(50)), "h1/2");
+        assert_eq!(lanes.assign("h1", at(25), at(55)), "h1/1");
+    }
+
+    #[test]
+    fn uses_peak_concurrency() {
+        /*
+         * No more than three of these are in progress at once, but putting
+         * the third in the first lane, idle since 2, would leave only the
+         * second lane, idle since 6, for the last, which began at 3.
+         */
+        let mut lanes = Lanes::default();
+        let names: Vec<String> = [(0, 2), (1, 6), (8, 10), (0, 10), (3, 11)]
+            .iter()
+            .map(|(begin, end)| lanes.assign("h1", at(*begin), at(*end)))
+            .collect();
+        assert_eq!(names, vec!["h1/0", "h1/1", "h1/1", "h1/2", "h1/0"]);
+    }
+}
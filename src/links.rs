@@ -0,0 +1,114 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use chrono::{DateTime, Utc};
+
+use crate::trace::TraceData;
+
+/*
+ * The name of the link a call travelled over: from the caller (an S3 client
+ * or, for internal RPCs, the peer server) to the server that handled it.
+ */
+pub fn link(td: &TraceData) -> String {
+    let client = if td.client.is_empty() {
+        "unknown"
+    } else {
+        &td.client
+    };
+
+    format!("{} -> {}", client, td.host)
+}
+
+#[derive(Default)]
+struct LinkStats {
+    calls: u64,
+    time_ns: i64,
+}
+
+/*
+ * LinkMatrix counts the calls made, and the time spent on them, between each
+ * caller and server. Calls with no caller, such as storage traces, aren't
+ * links and are left out.
+ */
+#[derive(Default)]
+pub struct LinkMatrix {
+    links: BTreeMap<(String, String), LinkStats>,
+}
+
+impl LinkMatrix {
+    pub fn add(&mut self, td: &TraceData, begin: DateTime<Utc>,
+        end: DateTime<Utc>) {
+
+        if td.client.is_empty() {
+            return;
+        }
+
+        let stats = self.links
+            .entry((td.client.clone(), td.host.clone()))
+            .or_default();
+        stats.calls += 1;
+        stats.time_ns += (end - begin).num_nanoseconds().unwrap_or(0);
+    }
+
+    /*
+     * Print the matrix with a row per caller and a column per server. Each
+     * cell holds the number of calls and their total duration.
+     */
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.links.is_empty() {
+            return Ok(());
+        }
+
+        let clients: BTreeSet<&str> = self.links.keys()
+            .map(|(c, _)| c.as_str())
+            .collect();
+        let hosts: BTreeSet<&str> = self.links.keys()
+            .map(|(_, h)| h.as_str())
+            .collect();
+
+        let cell = |client: &str, host: &str| {
+            match self.links.get(&(client.to_string(), host.to_string())) {
+                Some(s) => format!("{} / {:.1}ms", s.calls,
+                    s.time_ns as f64 / 1e6),
+                None => "-".to_string(),
+            }
+        };
+
+        let first = clients.iter().map(|c| c.len()).max().unwrap_or(0)
+            .max("caller".len());
+        let widths: Vec<usize> = hosts.iter()
+            .map(|h| clients.iter()
+                .map(|c| cell(c, h).len())
+                .max()
+                .unwrap_or(0)
+                .max(h.len()))
+            .collect();
+
+        writeln!(out, "calls / total time, by caller (row) and server \
+            (column):")?;
+
+        write!(out, "{:<w$}", "caller", w = first)?;
+        for (h, w) in hosts.iter().zip(&widths) {
+            write!(out, "  {:>w$}", h, w = w)?;
+        }
+        writeln!(out)?;
+
+        for c in &clients {
+            write!(out, "{:<w$}", c, w = first)?;
+            for (h, w) in hosts.iter().zip(&widths) {
+                write!(out, "  {:>w$}", cell(c, h), w = w)?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+}
@@ -8,101 +8,151 @@
 
 extern crate getopts;
 
+mod admin;
+mod alias;
+mod convert;
+mod correlate;
+mod drive;
+mod entity;
+mod failure;
+mod gaps;
+mod frame;
+mod input;
+mod lanes;
+mod links;
+mod merge;
+mod order;
+mod overlap;
+mod rules;
+mod sample;
+mod tags;
+mod schema;
+mod topology;
+mod trace;
+mod window;
+
 use std::env;
-use std::fs;
-use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use getopts::Options;
 
-use serde::Deserialize;
-use serde_json::Deserializer;
-use statemap::Statemap;
+use admin::AdminClient;
+use alias::Aliases;
+use convert::Converter;
+use correlate::{Correlate, Lifecycles};
+use drive::Drives;
+use entity::EntityKind;
+use failure::Failures;
+use gaps::Gaps;
+use lanes::Lanes;
+use links::LinkMatrix;
+use merge::{Merge, TraceStream};
+use order::{EntityOrder, SortKey};
+use overlap::Overlaps;
+use rules::Rules;
+use sample::{Sample, SampleMode};
+use tags::Tags;
+use topology::Topology;
+use trace::{Records, Schema, TraceData};
+use window::{parse_duration, Bound, Origin, Window};
 
-use chrono::{DateTime, Utc, NaiveDateTime};
+/*
+ * In follow mode, how often to emit the states collected so far.
+ */
+const FOLLOW_FLUSH: Duration = Duration::from_secs(1);
 
 /*
- * TraceData represents the default non-verbose MinIO trace format. If the
- * MinIO trace format changes in the future this will also need to be updated.
+ * In follow mode, how long an input can go without sending anything before
+ * we stop waiting for it to merge the others.
  */
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-#[allow(dead_code)]
-struct TraceData {
-    host: String,
-    time: DateTime<Utc>,
-    client: String,
-    call_stats: CallStats,
-    api: String,
-    path: String,
-    query: String,
-    status_code: u32,
-    status_msg: String,
-}
+const FOLLOW_IDLE: Duration = Duration::from_secs(1);
+
+/*
+ * Convert a stream of MinIO trace records into statemap-formatted records and
+ * print them to stdout.
+ */
+fn print_states<I>(records: I, mut conv: Converter) -> std::io::Result<()>
+    where I: Iterator<Item = std::io::Result<TraceData>> {
+
+    let mut sm = conv.statemap();
+
+    for td in records {
+        conv.record(&mut sm, &td?)?;
+    }
+
+    conv.emit(sm, &mut std::io::stdout().lock())?;
+
+    conv.finish()
 
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-#[allow(dead_code)]
-struct CallStats {
-    rx: u32,
-    tx: u32,
-    duration: u64,
-    time_to_first_byte: u32,
 }
 
 /*
- * Parse the MinIO trace data file and print statemap-formatted records to
- * stdout.
+ * Like print_states, but for input that may never end. Every FOLLOW_FLUSH
+ * the states collected since the last flush are written out as a complete
+ * statemap of their own, to a numbered file starting with 'prefix'. A
+ * statemap's states have to be listed before any of its records, so they
+ * can't all go to one stream that is added to as more records arrive. The
+ * files do agree on the states' values and the entities' names, though.
+ *
+ * Reading the input can block indefinitely, so it is done on its own thread.
  */
-fn print_states(filename: &str, title: &str, cluster: &str)
-    -> std::io::Result<()> {
-
-    let raw_data = fs::read_to_string(filename)?;
-
-    let mut sm = Statemap::new(title, Some(cluster.to_string()), None);
-
-    let state_iter = Deserializer::from_str(&raw_data)
-        .into_iter::<TraceData>();
-
-    for deserialize_result in state_iter {
-        let td = deserialize_result.expect("invalid minio json");
-
-        /*
-         * MinIO's trace data is sorted by _end_ time of operation, not _start_
-         * time. Further, MinIO doesn't report the start time of each operation.
-         * MinIO only reports the end time of each operation and the duration of
-         * the operation, so we must infer the start time based on this
-         * information.
-         */
-        let end_ns: u64 = td.time.timestamp_subsec_nanos().into();
-        let unix_end_time: u64 = (td.time.timestamp() * 1_000_000_000)
-            .try_into()
-            .expect("failed to make unix timestamp into ns timestamp");
-
-        let end_time_ns = unix_end_time + end_ns;
-        let begin_time_ns = end_time_ns - td.call_stats.duration;
-
-        let begin_s: i64 = (begin_time_ns / 1_000_000_000) as i64;
-        let begin_ns: u32 = (begin_time_ns % 1_000_000_000) as u32;
-
-        let dt = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(
-            begin_s, begin_ns), Utc);
-
-        /*
-         * Set this minio instance to be working on the given API request.
-         * Immediately after it is done serving an API request we switch it
-         * to the 'waiting' state.
-         */
-        sm.set_state(&td.host, &td.api, None, dt);
-        sm.set_state(&td.host, "waiting", None, td.time);
-    }
-    sm.set_state_color("waiting", "white");
+fn follow_states<I>(records: I, mut conv: Converter, prefix: &str)
+    -> std::io::Result<()>
+    where I: Iterator<Item = std::io::Result<TraceData>> + Send + 'static {
 
-    for state in sm {
-        println!("{}", state);
-    }
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for td in records {
+            if tx.send(td).is_err() {
+                break;
+            }
+        }
+    });
 
-    Ok(())
+    let mut sm = conv.statemap();
+    let mut pending = false;
+    let mut last_flush = Instant::now();
+    let mut flushes = 0;
+
+    loop {
+        let wait = FOLLOW_FLUSH
+            .checked_sub(last_flush.elapsed())
+            .unwrap_or_default();
+
+        let done = match rx.recv_timeout(wait) {
+            Ok(td) => {
+                conv.record(&mut sm, &td?)?;
+                pending = true;
+                false
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
 
+        if done || last_flush.elapsed() >= FOLLOW_FLUSH {
+            if pending {
+                let full = std::mem::replace(&mut sm, conv.statemap());
+                flushes += 1;
+                let path = format!("{}.{:06}", prefix, flushes);
+                let mut out = BufWriter::new(File::create(&path)?);
+                conv.emit(full, &mut out)?;
+                out.flush()?;
+                pending = false;
+            }
+            last_flush = Instant::now();
+        }
+
+        if done {
+            return conv.finish();
+        }
+    }
 }
 
 fn usage(opts: Options, msg: &str) {
@@ -111,7 +161,10 @@ fn usage(opts: Options, msg: &str) {
 
     let usg = format!("minio-statemap - {}", synopsis);
     let ex_usg = "Example usage:\n \
-        ./minio-statemap -i ./my_minio_trace.out > minio_states\n"
+        ./minio-statemap -i ./my_minio_trace.out > minio_states\n \
+        ./minio-statemap -i node1.out -i node2.out > minio_states\n \
+        mc admin trace -a --json min0 | ./minio-statemap > minio_states\n \
+        ./minio-statemap --endpoint https://minio1:9000 > minio_states\n"
         .to_string();
     println!("{}", opts.usage(&usg));
     println!("{}", ex_usg);
@@ -123,10 +176,12 @@ fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
     let mut opts = Options::new();
 
-    opts.reqopt("i",
-                "input-file",
-                "path to minio trace file to be parsed",
-                "FILE");
+    opts.optmulti("i",
+                  "input-file",
+                  "path to minio trace file to be parsed, optionally gzip, \
+                  zstd or xz compressed ('-' or omitted for stdin). May be \
+                  repeated, or be a directory or glob, to merge traces",
+                  "FILE");
 
     opts.optopt("c",
                 "cluster-name",
@@ -136,6 +191,160 @@ fn main() -> std::io::Result<()> {
                 "title",
                 "statemap title",
                 "TITLE");
+    opts.optopt("",
+                "entity",
+                "what each line of the statemap represents: 'host' \
+                (default), 'client', 'bucket', 'api', 'drive' (host and \
+                drive), 'request' (an S3 request and the calls it made), \
+                'link' (caller and server), 'object' or 'prefix:N' (the \
+                first N components of the object key)",
+                "ENTITY");
+    opts.optopt("",
+                "rules",
+                "TOML or JSON file of rules that name and color states, e.g. \
+                to merge related APIs into one state",
+                "FILE");
+    opts.optopt("",
+                "sort-entities",
+                "order the lines of the statemap by 'name', 'first-seen', \
+                'busy' (busiest first), 'errors' (most first) or \
+                'topology' (by pool and erasure set)",
+                "ORDER");
+    opts.optflag("",
+                 "group-headers",
+                 "with --sort-entities, introduce each group of related \
+                 lines, such as the drives of one server, with a header");
+    opts.optopt("",
+                "aliases",
+                "file of friendly names for servers and clients, one \
+                'NAME ALIAS' per line",
+                "FILE");
+    opts.optmulti("",
+                  "rewrite",
+                  "rename servers and clients matching a regex, e.g. \
+                  '^172\\.20\\.0\\.(\\d+):9000$=minio$1'. May be repeated",
+                  "PATTERN=REPLACEMENT");
+    opts.optmulti("",
+                  "pool",
+                  "a server pool, in the endpoint syntax given to `minio \
+                  server`, e.g. 'http://minio{1...4}/data{1...4}'. Host and \
+                  drive entities are grouped by pool and erasure set. May be \
+                  repeated",
+                  "ENDPOINTS");
+    opts.optopt("",
+                "topology",
+                "file of server pools, one per line, in the same form as \
+                --pool and optionally followed by the erasure set size",
+                "FILE");
+    opts.optopt("",
+                "set-drive-count",
+                "erasure set size of each --pool, if not MinIO's default",
+                "N");
+    opts.optflag("l",
+                 "lanes",
+                 "split each entity into lanes so that concurrent operations \
+                 are shown side by side, for non-serial workloads");
+    opts.optflag("",
+                 "link-matrix",
+                 "print the number of calls and time spent between each \
+                 caller and server to stderr");
+    opts.optflag("",
+                 "tags",
+                 "tag each state with the details of its call, such as its \
+                 path, status and bytes transferred, to show on hover");
+    opts.optflag("",
+                 "phases",
+                 "draw each call as 'API:processing' until it sent its first \
+                 byte and 'API:transfer' after, where that time is known");
+    opts.optopt("",
+                "between-ops",
+                "draw gaps between calls shorter than this, such as '50us', \
+                as 'between ops' rather than idle",
+                "DURATION");
+    opts.optopt("",
+                "no-data",
+                "draw gaps between calls longer than this, such as '10s', \
+                as 'no data' rather than idle",
+                "DURATION");
+    opts.optopt("",
+                "idle-state",
+                "name of the state for idle gaps between calls (default \
+                'waiting')",
+                "NAME");
+    opts.optopt("",
+                "idle-color",
+                "color of the idle state (default white)",
+                "COLOR");
+    opts.optflag("",
+                 "no-idle",
+                 "draw idle gaps as 'between ops', leaving no idle state");
+    opts.optflag("",
+                 "serial",
+                 "fail if operations overlap on the same entity, rather than \
+                 reporting them and carrying on");
+    opts.optopt("",
+                "schema",
+                "trace format: 'auto' (default), 'legacy', 'legacy-verbose' \
+                or 'traceinfo'",
+                "SCHEMA");
+    opts.optopt("",
+                "start",
+                "ignore activity before this time, given in RFC 3339 format \
+                or as an offset from the first record such as '30s'",
+                "TIME");
+    opts.optopt("",
+                "end",
+                "ignore activity after this time, given in RFC 3339 format \
+                or as an offset from the first record such as '1m'",
+                "TIME");
+    opts.optopt("",
+                "sample",
+                "keep one in N S3 requests, along with the internal calls \
+                they made: 'every:N' for every Nth request or 'path:N' to \
+                choose by object path",
+                "MODE:N");
+    opts.optflag("f",
+                 "follow",
+                 "keep reading input files as they grow, like `tail -F`, \
+                 writing a new statemap for each second of records");
+    opts.optopt("o",
+                "output-prefix",
+                "with --follow or --endpoint, write each second's statemap \
+                to a file named PREFIX.000001, PREFIX.000002 and so on",
+                "PREFIX");
+    opts.optopt("e",
+                "endpoint",
+                "read a live trace from this MinIO server rather than from \
+                files",
+                "URL");
+    opts.optopt("",
+                "access-key",
+                "access key for --endpoint (default $MINIO_ACCESS_KEY)",
+                "KEY");
+    opts.optopt("",
+                "secret-key",
+                "secret key for --endpoint (default $MINIO_SECRET_KEY)",
+                "KEY");
+    opts.optopt("",
+                "region",
+                "region for --endpoint request signing (default us-east-1)",
+                "REGION");
+    opts.optmulti("",
+                  "trace-type",
+                  "with --endpoint, trace only this type of call, e.g. 's3', \
+                  'internal' or 'storage'. May be repeated",
+                  "TYPE");
+    opts.optopt("",
+                "ca-cert",
+                "with --endpoint, trust the CA certificates in this PEM file",
+                "FILE");
+    opts.optflag("",
+                 "insecure",
+                 "with --endpoint, don't verify the server's TLS certificate");
+    opts.optflag("",
+                 "strict",
+                 "exit with an error at the first malformed trace record \
+                 rather than skipping it");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -145,11 +354,284 @@ fn main() -> std::io::Result<()> {
         },
     };
 
-    let ifile = matches.opt_str("input-file").unwrap();
+    let endpoint = matches.opt_str("endpoint");
+
+    /* When tracing a live server, stdin is only read if asked for. */
+    let ifile_args = matches.opt_strs("input-file");
+    let ifiles = if endpoint.is_some() && ifile_args.is_empty() {
+        Vec::new()
+    } else {
+        input::expand_inputs(&ifile_args)?
+    };
     let cluster = matches.opt_get_default(
         "cluster-name", "minio cluster".to_string()).unwrap();
     let title = matches.opt_get_default(
         "title", "MinIO".to_string()).unwrap();
 
-    print_states(&ifile, &title, &cluster)
+    let schema = match matches.opt_str("schema").as_deref() {
+        None | Some("auto") => None,
+        Some(s) => match s.parse::<Schema>() {
+            Ok(s) => Some(s),
+            Err(e) => {
+                usage(opts, &e);
+                return Ok(())
+            },
+        },
+    };
+
+    let mut bounds = Vec::new();
+    for opt in &["start", "end"] {
+        bounds.push(match matches.opt_str(opt) {
+            None => None,
+            Some(s) => match s.parse::<Bound>() {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    usage(opts, &e);
+                    return Ok(())
+                },
+            },
+        });
+    }
+
+    let sample = match matches.opt_str("sample") {
+        None => None,
+        Some(s) => match s.parse::<SampleMode>() {
+            Ok(m) => Some(m),
+            Err(e) => {
+                usage(opts, &e);
+                return Ok(())
+            },
+        },
+    };
+
+    let mut aliases = Aliases::default();
+    if let Some(path) = matches.opt_str("aliases") {
+        aliases.load(&path)?;
+    }
+    for rule in matches.opt_strs("rewrite") {
+        if let Err(e) = aliases.add_rule(&rule) {
+            usage(opts, &e);
+            return Ok(())
+        }
+    }
+
+    let mut topology = Topology::default();
+    if let Some(path) = matches.opt_str("topology") {
+        topology.load(&path)?;
+    }
+    let set_size = match matches.opt_get::<usize>("set-drive-count") {
+        Ok(n) => n,
+        Err(e) => {
+            usage(opts, &format!("invalid erasure set size: {}", e));
+            return Ok(())
+        },
+    };
+    for pool in matches.opt_strs("pool") {
+        if let Err(e) = topology.add_pool(&pool, set_size) {
+            usage(opts, &e);
+            return Ok(())
+        }
+    }
+    topology.rename(&aliases);
+
+    let mut drives = Drives::default();
+    topology.add_mounts(&mut drives);
+
+    let entity = match matches.opt_str("entity") {
+        None => EntityKind::Host,
+        Some(s) => match s.parse::<EntityKind>() {
+            Ok(e) => e,
+            Err(e) => {
+                usage(opts, &e);
+                return Ok(())
+            },
+        },
+    };
+
+    /*
+     * A live trace never ends, so it is always followed. Followed input
+     * gives a statemap every second, which each go in a file of their own.
+     */
+    let follow = matches.opt_present("follow");
+    let live = follow || endpoint.is_some();
+    let prefix = matches.opt_str("output-prefix");
+    if live && prefix.is_none() {
+        usage(opts, "--follow and --endpoint need --output-prefix");
+        return Ok(())
+    }
+
+    let mut order = match matches.opt_str("sort-entities") {
+        None => None,
+        Some(s) => match s.parse::<SortKey>() {
+            Ok(key) => Some(EntityOrder::new(key,
+                matches.opt_present("group-headers"))),
+            Err(e) => {
+                usage(opts, &e);
+                return Ok(())
+            },
+        },
+    };
+    if let (true, Some(order)) = (live, &mut order) {
+        order.keep_names();
+    }
+
+    let rules = match matches.opt_str("rules") {
+        Some(path) => Rules::load(&path)?,
+        None => Rules::default(),
+    };
+
+    let mut thresholds = Vec::new();
+    for opt in &["between-ops", "no-data"] {
+        thresholds.push(match matches.opt_str(opt) {
+            None => None,
+            Some(s) => match parse_duration(&s) {
+                Some(d) => Some(d),
+                None => {
+                    usage(opts, &format!("invalid duration '{}'", s));
+                    return Ok(())
+                },
+            },
+        });
+    }
+    let idle = if matches.opt_present("no-idle") {
+        None
+    } else {
+        Some((matches.opt_get_default("idle-state",
+            "waiting".to_string()).unwrap(),
+            matches.opt_get_default("idle-color",
+            "white".to_string()).unwrap()))
+    };
+    let gaps = match Gaps::new(idle, thresholds[0], thresholds[1]) {
+        Ok(g) => g,
+        Err(e) => {
+            usage(opts, &e);
+            return Ok(())
+        },
+    };
+
+    let overlaps = Arc::new(AtomicU64::new(0));
+    let origin = Origin::default();
+
+    let conv = Converter {
+        title,
+        cluster,
+        entity,
+        window: Window::new(bounds[0], bounds[1], origin.clone()),
+        lanes: if matches.opt_present("lanes") {
+            Some(Lanes::default())
+        } else {
+            None
+        },
+        overlaps: Overlaps::new(matches.opt_present("serial"),
+            Arc::clone(&overlaps)),
+        lifecycles: Lifecycles::default(),
+        matrix: if matches.opt_present("link-matrix") {
+            Some(LinkMatrix::default())
+        } else {
+            None
+        },
+        topology: if topology.is_empty() {
+            None
+        } else {
+            Some(topology)
+        },
+        drives,
+        order,
+        rules,
+        failures: Failures::default(),
+        tags: if matches.opt_present("tags") {
+            Some(Tags::default())
+        } else {
+            None
+        },
+        phases: matches.opt_present("phases"),
+        gaps,
+        states: Vec::new(),
+    };
+
+    let strict = matches.opt_present("strict");
+    let skipped = Arc::new(AtomicU64::new(0));
+
+    let mut streams: Vec<TraceStream> = Vec::new();
+    for ifile in &ifiles {
+        let input = input::open_input(ifile, follow)?;
+        streams.push(Box::new(Records::new(ifile, input, schema, strict,
+            Arc::clone(&skipped))));
+    }
+
+    if let Some(endpoint) = endpoint {
+        let key = |opt: &str, var: &str| {
+            matches.opt_str(opt)
+                .or_else(|| env::var(var).ok())
+                .unwrap_or_default()
+        };
+
+        let client = AdminClient {
+            access_key: key("access-key", "MINIO_ACCESS_KEY"),
+            secret_key: key("secret-key", "MINIO_SECRET_KEY"),
+            region: matches.opt_get_default(
+                "region", "us-east-1".to_string()).unwrap(),
+            ca_cert: matches.opt_str("ca-cert"),
+            insecure: matches.opt_present("insecure"),
+            endpoint,
+        };
+
+        let input = client.trace(&matches.opt_strs("trace-type"))?;
+        streams.push(Box::new(Records::new(&client.endpoint, input, schema,
+            strict, Arc::clone(&skipped))));
+    }
+
+    let merge = if live {
+        Merge::follow(streams, FOLLOW_IDLE)
+    } else {
+        Merge::new(streams)
+    };
+    let merged: TraceStream = if aliases.is_empty() {
+        Box::new(merge)
+    } else {
+        Box::new(merge.map(move |r| r.map(|td| aliases.apply(td))))
+    };
+    let merged: TraceStream = Box::new(merged.inspect(move |r| {
+        if let Ok(td) = r {
+            origin.note(td);
+        }
+    }));
+    /*
+     * Sampling keeps or drops each call along with the request it was made
+     * for, so it needs calls correlated with their requests too.
+     */
+    let records: TraceStream = match (entity, sample) {
+        (EntityKind::Request, _) | (_, Some(_)) =>
+            Box::new(Correlate::new(merged)),
+        _ => merged,
+    };
+    let records: TraceStream = match sample {
+        Some(mode) => Box::new(Sample::new(records, mode)),
+        None => records,
+    };
+
+    let result = if let (true, Some(prefix)) = (live, &prefix) {
+        follow_states(records, conv, prefix)
+    } else {
+        print_states(records, conv)
+    };
+
+    if let Err(e) = result {
+        eprintln!("minio-statemap: {}", e);
+        process::exit(1);
+    }
+
+    let skipped = skipped.load(Ordering::Relaxed);
+    if skipped > 0 {
+        eprintln!("minio-statemap: skipped {} malformed trace record(s)",
+            skipped);
+    }
+
+    let overlaps = overlaps.load(Ordering::Relaxed);
+    if overlaps > 0 {
+        eprintln!("minio-statemap: found {} overlapping operation(s); the \
+            statemap may be wrong (try --lanes)", overlaps);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,356 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::trace::TraceData;
+
+pub type TraceStream =
+    Box<dyn Iterator<Item = io::Result<TraceData>> + Send>;
+
+/*
+ * Merge combines several TraceData streams into a single stream ordered by
+ * end time. Each input stream is expected to already be in end time order,
+ * which is how MinIO emits trace records.
+ *
+ * Captures taken at the same time (e.g. a capture that was restarted, or one
+ * `mc admin trace` per node against a cluster) can contain the same record
+ * more than once. Duplicates always share an end time, so they are dropped by
+ * comparing each record against those other inputs have already emitted for
+ * that instant. A record repeated within one input is a call that really was
+ * made twice, so it is kept.
+ *
+ * Errors from the input streams are passed along as soon as they are seen.
+ */
+pub struct Merge {
+    inputs: Inputs,
+    /* The records emitted for the latest instant, and their streams. */
+    recent: Vec<(usize, TraceData)>,
+}
+
+enum Inputs {
+    Pulled(Pulled),
+    Followed(Followed),
+}
+
+impl Merge {
+    pub fn new(streams: Vec<TraceStream>) -> Merge {
+        Merge {
+            inputs: Inputs::Pulled(Pulled {
+                heads: streams.iter().map(|_| None).collect(),
+                streams,
+                heap: BinaryHeap::new(),
+                error: None,
+                primed: false,
+                refill: None,
+            }),
+            recent: Vec::new(),
+        }
+    }
+
+    /*
+     * Merge streams that may go quiet for any length of time, such as
+     * followed files. Any stream that has been quiet for 'idle' is passed
+     * over, rather than holding up the others until it next has something.
+     */
+    pub fn follow(streams: Vec<TraceStream>, idle: Duration) -> Merge {
+        let (tx, rx) = mpsc::channel();
+        let n = streams.len();
+
+        for (i, stream) in streams.into_iter().enumerate() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for r in stream {
+                    if tx.send((i, Some(r))).is_err() {
+                        return;
+                    }
+                }
+                let _ = tx.send((i, None));
+            });
+        }
+
+        let now = Instant::now();
+        Merge {
+            inputs: Inputs::Followed(Followed {
+                rx,
+                queues: (0..n).map(|_| VecDeque::new()).collect(),
+                heard: vec![now; n],
+                done: vec![false; n],
+                idle,
+            }),
+            recent: Vec::new(),
+        }
+    }
+}
+
+/*
+ * Pulled reads its streams itself, a record at a time from whichever stream
+ * has the earliest record waiting. That needs a record from every stream, so
+ * it suits inputs that always have one to give or have ended.
+ */
+struct Pulled {
+    streams: Vec<TraceStream>,
+    heads: Vec<Option<TraceData>>,
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, usize)>>,
+    error: Option<io::Error>,
+    primed: bool,
+    refill: Option<usize>,
+}
+
+impl Pulled {
+    /* Pull the next record from stream 'i' and queue it for merging. */
+    fn advance(&mut self, i: usize) {
+        match self.streams[i].next() {
+            Some(Ok(td)) => {
+                self.heap.push(Reverse((td.time, i)));
+                self.heads[i] = Some(td);
+            },
+            Some(Err(e)) => self.error = Some(e),
+            None => (),
+        }
+    }
+
+    /*
+     * Take the earliest record across all streams, and the stream it came
+     * from, or None once every stream is exhausted.
+     */
+    fn pop(&mut self) -> Option<io::Result<(usize, TraceData)>> {
+        /*
+         * Don't touch the streams until we're first asked for a record, as
+         * reading may block.
+         */
+        if !self.primed {
+            self.primed = true;
+            for i in 0..self.streams.len() {
+                self.advance(i);
+            }
+        }
+
+        /*
+         * Likewise, the stream we took the last record from is only refilled
+         * now, so that record wasn't held back waiting for the next one.
+         */
+        if let Some(i) = self.refill.take() {
+            self.advance(i);
+        }
+
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        let Reverse((_, i)) = self.heap.pop()?;
+        self.refill = Some(i);
+        self.heads[i].take().map(|td| Ok((i, td)))
+    }
+}
+
+/*
+ * A message from a followed stream's reader thread: its next record, or
+ * None once it has ended.
+ */
+type Message = (usize, Option<io::Result<TraceData>>);
+
+/*
+ * Followed reads each stream on a thread of its own, and merges only up to
+ * a watermark: the earliest record it has from any stream that has been
+ * heard from recently. Until every such stream has a record waiting, one of
+ * them might yet send something earlier, so we wait for it. A stream that
+ * stays quiet for longer than 'idle' is left out of the watermark until it
+ * sends something again; anything it then sends that's older than records
+ * already passed on is passed on late rather than dropped.
+ */
+struct Followed {
+    rx: Receiver<Message>,
+    queues: Vec<VecDeque<TraceData>>,
+    /* When each stream last sent anything. */
+    heard: Vec<Instant>,
+    done: Vec<bool>,
+    idle: Duration,
+}
+
+impl Followed {
+    fn receive(&mut self, (i, msg): Message) -> Option<io::Error> {
+        match msg {
+            Some(Ok(td)) => {
+                self.heard[i] = Instant::now();
+                self.queues[i].push_back(td);
+                None
+            },
+            Some(Err(e)) => {
+                self.heard[i] = Instant::now();
+                Some(e)
+            },
+            None => {
+                self.done[i] = true;
+                None
+            },
+        }
+    }
+
+    fn pop(&mut self) -> Option<io::Result<(usize, TraceData)>> {
+        loop {
+            while let Ok(msg) = self.rx.try_recv() {
+                if let Some(e) = self.receive(msg) {
+                    return Some(Err(e));
+                }
+            }
+
+            /* Streams that are still active but have nothing waiting. */
+            let waiting: Vec<usize> = (0..self.queues.len())
+                .filter(|&i| !self.done[i] && self.queues[i].is_empty() &&
+                    self.heard[i].elapsed() < self.idle)
+                .collect();
+
+            if waiting.is_empty() {
+                let earliest = (0..self.queues.len())
+                    .filter(|&i| !self.queues[i].is_empty())
+                    .min_by_key(|&i| self.queues[i][0].time);
+                if let Some(i) = earliest {
+                    let td = self.queues[i].pop_front()?;
+                    return Some(Ok((i, td)));
+                }
+                if self.done.iter().all(|&d| d) {
+                    return None;
+                }
+            }
+
+            /*
+             * Wait for something to arrive, or for the first of the waiting
+             * streams to be quiet for long enough that we can pass it over.
+             */
+            let timeout = waiting.iter()
+                .map(|&i| self.idle.saturating_sub(self.heard[i].elapsed()))
+                .min();
+            let msg = match timeout {
+                Some(t) => match self.rx.recv_timeout(t) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                },
+                None => match self.rx.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => return None,
+                },
+            };
+            if let Some(e) = self.receive(msg) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<TraceData>;
+
+    fn next(&mut self) -> Option<io::Result<TraceData>> {
+        loop {
+            let next = match &mut self.inputs {
+                Inputs::Pulled(p) => p.pop(),
+                Inputs::Followed(f) => f.pop(),
+            };
+            let (i, td) = match next? {
+                Ok(r) => r,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.recent.first() {
+                Some((_, r)) if r.time != td.time => self.recent.clear(),
+                _ => (),
+            }
+
+            if self.recent.iter().any(|(j, r)| *j != i && *r == td) {
+                continue;
+            }
+
+            self.recent.push((i, td.clone()));
+            return Some(Ok(td));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::call;
+
+    fn stream(records: Vec<TraceData>) -> TraceStream {
+        Box::new(records.into_iter().map(Ok))
+    }
+
+    fn apis(merge: Merge) -> Vec<String> {
+        merge.map(|r| r.unwrap().api).collect()
+    }
+
+    #[test]
+    fn merges_by_end_time() {
+        let merge = Merge::new(vec![
+            stream(vec![call("s3.A", "h1", 0, 1), call("s3.C", "h1", 0, 3)]),
+            stream(vec![call("s3.B", "h2", 0, 2), call("s3.D", "h2", 0, 4)]),
+        ]);
+        assert_eq!(apis(merge), vec!["s3.A", "s3.B", "s3.C", "s3.D"]);
+    }
+
+    #[test]
+    fn drops_duplicates_across_inputs() {
+        let merge = Merge::new(vec![
+            stream(vec![call("s3.A", "h1", 0, 1), call("s3.B", "h1", 0, 2)]),
+            stream(vec![call("s3.A", "h1", 0, 1), call("s3.C", "h1", 0, 3)]),
+        ]);
+        assert_eq!(apis(merge), vec!["s3.A", "s3.B", "s3.C"]);
+    }
+
+    #[test]
+    fn keeps_repeats_within_an_input() {
+        let merge = Merge::new(vec![
+            stream(vec![call("s3.A", "h1", 0, 1), call("s3.A", "h1", 0, 1)]),
+            stream(vec![call("s3.A", "h1", 0, 1)]),
+        ]);
+        assert_eq!(apis(merge), vec!["s3.A", "s3.A"]);
+    }
+
+    #[test]
+    fn follows_past_idle_input() {
+        let (_tx, rx) = mpsc::channel::<io::Result<TraceData>>();
+        let idle: TraceStream = Box::new(rx.into_iter());
+
+        let merge = Merge::follow(vec![
+            stream(vec![call("s3.A", "h1", 0, 1), call("s3.B", "h1", 0, 2)]),
+            idle,
+        ], Duration::from_millis(50));
+
+        let started = Instant::now();
+        let apis: Vec<String> = merge.take(2).map(|r| r.unwrap().api)
+            .collect();
+        assert_eq!(apis, vec!["s3.A", "s3.B"]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn follow_waits_for_active_inputs() {
+        let (tx, rx) = mpsc::channel();
+        let slow: TraceStream = Box::new(rx.into_iter());
+
+        let merge = Merge::follow(vec![
+            stream(vec![call("s3.B", "h1", 0, 2), call("s3.D", "h1", 0, 4)]),
+            slow,
+        ], Duration::from_secs(60));
+
+        tx.send(Ok(call("s3.A", "h2", 0, 1))).unwrap();
+        tx.send(Ok(call("s3.C", "h2", 0, 3))).unwrap();
+        drop(tx);
+
+        assert_eq!(apis(merge), vec!["s3.A", "s3.B", "s3.C", "s3.D"]);
+    }
+}
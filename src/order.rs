@@ -0,0 +1,359 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::trace::TraceData;
+
+/* How to order the lines of the statemap. */
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    /* By name, with any numbers in names compared by value. */
+    Name,
+    /* By when each entity was first busy. */
+    FirstSeen,
+    /* Busiest first. */
+    Busy,
+    /* Most failed calls first. */
+    Errors,
+    /* By server pool and erasure set (see topology.rs), then by name. */
+    Topology,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortKey, String> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "first-seen" => Ok(SortKey::FirstSeen),
+            "busy" => Ok(SortKey::Busy),
+            "errors" => Ok(SortKey::Errors),
+            "topology" => Ok(SortKey::Topology),
+            _ => Err(format!("unknown sort order '{}': expected 'name', \
+                'first-seen', 'busy', 'errors' or 'topology'", s)),
+        }
+    }
+}
+
+/*
+ * The width of the ranks in names that are kept for the rest of the run, which
+ * must be fixed up front so that they go on sorting in order.
+ */
+const KEPT_RANK_WIDTH: usize = 4;
+
+struct EntityStats {
+    group: Option<String>,
+    first_seen: DateTime<Utc>,
+    busy_ns: i64,
+    errors: u64,
+}
+
+/*
+ * EntityOrder decides the order the statemap's entities are drawn in.
+ *
+ * The statemap renderer lays entities out by name, so the order is imposed by
+ * prefixing each name with its rank, e.g. '03 minio7:9000'. Entities in the
+ * same group (such as the drives of one server) are kept together, and with
+ * headers turned on each group is introduced by an entity of its own, named
+ * after the group, that sorts just ahead of its members.
+ *
+ * Every entity is also given a description naming its group, and these are
+ * printed in order ahead of the states so that renderers that lay entities
+ * out in the order they first appear agree.
+ *
+ * When a statemap is printed every second, an entity's name has to stay the
+ * same from one to the next. Names are then kept once given, and entities
+ * that turn up later are placed after those already named.
+ */
+pub struct EntityOrder {
+    key: SortKey,
+    headers: bool,
+    stats: HashMap<String, EntityStats>,
+    kept: Option<Kept>,
+}
+
+/* The names given so far, when they're being kept. */
+#[derive(Default)]
+struct Kept {
+    names: HashMap<String, String>,
+    /* group => header name */
+    headers: HashMap<String, String>,
+    rank: usize,
+}
+
+impl EntityOrder {
+    pub fn new(key: SortKey, headers: bool) -> EntityOrder {
+        EntityOrder {
+            key,
+            headers,
+            stats: HashMap::new(),
+            kept: None,
+        }
+    }
+
+    /* Keep the names entities are given for the rest of the run. */
+    pub fn keep_names(&mut self) {
+        self.kept.get_or_insert_with(Kept::default);
+    }
+
+    pub fn key(&self) -> SortKey {
+        self.key
+    }
+
+    pub fn add(&mut self, entity: &str, group: Option<String>, td: &TraceData,
+        begin: DateTime<Utc>, end: DateTime<Utc>) {
+
+        let stats = self.stats.entry(entity.to_string())
+            .or_insert_with(|| EntityStats {
+                group,
+                first_seen: begin,
+                busy_ns: 0,
+                errors: 0,
+            });
+
+        stats.first_seen = stats.first_seen.min(begin);
+        stats.busy_ns += (end - begin).num_nanoseconds().unwrap_or(0);
+        if td.failure().is_some() {
+            stats.errors += 1;
+        }
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let (sa, sb) = (&self.stats[a], &self.stats[b]);
+
+        let by_key = match self.key {
+            SortKey::Name | SortKey::Topology => Ordering::Equal,
+            SortKey::FirstSeen => sa.first_seen.cmp(&sb.first_seen),
+            SortKey::Busy => sb.busy_ns.cmp(&sa.busy_ns),
+            SortKey::Errors => sb.errors.cmp(&sa.errors),
+        };
+
+        by_key.then_with(|| natural_cmp(a, b))
+    }
+
+    /*
+     * Work out the name each entity is drawn under, and the headers (as
+     * name and group pairs) to draw, in order, along with the last rank
+     * given out.
+     */
+    fn arrange(&self)
+        -> (HashMap<String, String>, Vec<(String, String)>, usize) {
+
+        let kept = self.kept.as_ref();
+        let mut entities: Vec<&str> = self.stats.keys()
+            .map(|e| e.as_str())
+            .filter(|e| !kept.is_some_and(|k| k.names.contains_key(*e)))
+            .collect();
+        entities.sort_by(|a, b| self.compare(a, b));
+
+        /*
+         * Groups are ranked by their first member, and members keep their
+         * order within the group.
+         */
+        let mut groups: Vec<Option<&str>> = Vec::new();
+        for e in &entities {
+            let group = self.stats[*e].group.as_deref();
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+
+        let total = entities.len() + if self.headers { groups.len() } else {
+            0 };
+        let width = match kept {
+            Some(_) => KEPT_RANK_WIDTH,
+            None => total.to_string().len(),
+        };
+        let mut rank = kept.map_or(0, |k| k.rank);
+        let mut names = HashMap::new();
+        let mut headers = Vec::new();
+
+        if let Some(k) = kept {
+            names.extend(k.names.clone());
+            headers.extend(k.headers.iter()
+                .map(|(g, h)| (h.clone(), g.clone())));
+        }
+
+        for group in groups {
+            let headed = kept.is_some_and(|k| group
+                .is_some_and(|g| k.headers.contains_key(g)));
+            if let (true, false, Some(g)) = (self.headers, headed, group) {
+                rank += 1;
+                headers.push((format!("{:0w$} [{}]", rank, g, w = width),
+                    g.to_string()));
+            }
+            for e in &entities {
+                if self.stats[*e].group.as_deref() == group {
+                    rank += 1;
+                    names.insert(e.to_string(),
+                        format!("{:0w$} {}", rank, e, w = width));
+                }
+            }
+        }
+
+        (names, headers, rank)
+    }
+
+    /*
+     * Rename the entities in a statemap's state records and print them, led
+     * by the entities' descriptions and any group headers.
+     */
+    pub fn print(&mut self, states: impl Iterator<Item = String>,
+        out: &mut dyn Write) -> io::Result<()> {
+
+        let (names, headers, rank) = self.arrange();
+
+        let mut present = BTreeSet::new();
+        let mut records = Vec::new();
+        for state in states {
+            let mut value: Value = match serde_json::from_str(&state) {
+                Ok(v) => v,
+                Err(_) => {
+                    records.push(state);
+                    continue;
+                },
+            };
+            let renamed = value["entity"].as_str()
+                .and_then(|e| names.get(e));
+            if let Some(name) = renamed {
+                present.insert(name.clone());
+                value["entity"] = json!(name);
+            }
+            records.push(value.to_string());
+        }
+
+        /*
+         * Only describe the entities, and introduce the groups, that appear
+         * in this statemap. The ranks sort them into order.
+         */
+        let mut descriptions: Vec<(String, String)> = names.iter()
+            .filter(|(_, name)| present.contains(*name))
+            .map(|(entity, name)| {
+                let group = self.stats[entity].group.clone();
+                (name.clone(), group.unwrap_or_else(|| entity.clone()))
+            })
+            .collect();
+        let groups: BTreeSet<&String> = descriptions.iter()
+            .map(|(_, g)| g)
+            .collect();
+        let shown: Vec<(String, String)> = headers.iter()
+            .filter(|(_, g)| groups.contains(g))
+            .cloned()
+            .collect();
+        descriptions.extend(shown);
+        descriptions.sort();
+
+        for (name, description) in descriptions {
+            writeln!(out, "{}", json!({
+                "entity": name,
+                "description": description,
+            }))?;
+        }
+        for record in records {
+            writeln!(out, "{}", record)?;
+        }
+
+        if let Some(kept) = &mut self.kept {
+            kept.headers = headers.into_iter().map(|(h, g)| (g, h)).collect();
+            kept.names = names;
+            kept.rank = rank;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Compare two names as a person would, so that 'minio2' comes before
+ * 'minio10'. Names that differ only in leading zeros, such as 'data01' and
+ * 'data1', are still told apart, so that sorting them is stable.
+ */
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (name_a, name_b) = (a, b);
+    let mut a = a;
+    let mut b = b;
+
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return name_a.cmp(name_b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) if ca.is_ascii_digit() &&
+                cb.is_ascii_digit() => {
+                let na = a.find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(a.len());
+                let nb = b.find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(b.len());
+                let (da, db) = (a[..na].trim_start_matches('0'),
+                    b[..nb].trim_start_matches('0'));
+
+                let ord = da.len().cmp(&db.len()).then_with(|| da.cmp(db));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[na..];
+                b = &b[nb..];
+            },
+            (Some(ca), Some(cb)) => {
+                if ca != cb {
+                    return ca.cmp(&cb);
+                }
+                a = &a[ca.len_utf8()..];
+                b = &b[cb.len_utf8()..];
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert_eq!(natural_cmp("minio2", "minio10"), Ordering::Less);
+        assert_eq!(natural_cmp("minio10:9000", "minio9:9000"),
+            Ordering::Greater);
+        assert_eq!(natural_cmp("pool1/set10", "pool1/set9"),
+            Ordering::Greater);
+        assert_eq!(natural_cmp("pool2/set1", "pool10/set1"), Ordering::Less);
+        assert_eq!(natural_cmp("data007", "data8"), Ordering::Less);
+    }
+
+    #[test]
+    fn compares_text_by_character() {
+        assert_eq!(natural_cmp("minio", "minio1"), Ordering::Less);
+        assert_eq!(natural_cmp("a1b", "a1c"), Ordering::Less);
+        assert_eq!(natural_cmp("b1", "a2"), Ordering::Greater);
+        assert_eq!(natural_cmp("é1", "é1"), Ordering::Equal);
+        assert_eq!(natural_cmp("", ""), Ordering::Equal);
+    }
+
+    #[test]
+    fn tells_leading_zeros_apart() {
+        assert_ne!(natural_cmp("data01", "data1"), Ordering::Equal);
+        assert_eq!(natural_cmp("data01", "data1"),
+            natural_cmp("data1", "data01").reverse());
+    }
+
+    #[test]
+    fn sorts_names() {
+        let mut names = vec!["m10:9000", "m1:9000", "m2:9000", "client",
+            "m1:9001"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["client", "m1:9000", "m1:9001", "m2:9000",
+            "m10:9000"]);
+    }
+}
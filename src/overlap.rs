@@ -0,0 +1,140 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+
+/*
+ * Only the first few overlaps are described on stderr. A workload that
+ * wasn't serial at all would otherwise bury everything else in reports.
+ */
+const MAX_REPORTED: u64 = 10;
+
+struct Operation {
+    host: String,
+    api: String,
+    begin: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/*
+ * Overlaps looks for operations on the same entity whose intervals overlap.
+ * A statemap entity can only be in one state at a time, so when this happens
+ * the second operation silently overwrites the first and the statemap no
+ * longer reflects what really happened.
+ *
+ * Trace records arrive in end time order, so an operation overlaps some
+ * earlier one on its entity exactly when it began before the most recent one
+ * ended. Only that most recent operation needs to be remembered.
+ */
+pub struct Overlaps {
+    last: HashMap<String, Operation>,
+    /* Fail at the first overlap rather than reporting it and carrying on. */
+    serial: bool,
+    count: Arc<AtomicU64>,
+}
+
+impl Overlaps {
+    pub fn new(serial: bool, count: Arc<AtomicU64>) -> Overlaps {
+        Overlaps {
+            last: HashMap::new(),
+            serial,
+            count,
+        }
+    }
+
+    pub fn check(&mut self, entity: &str, host: &str, api: &str,
+        begin: DateTime<Utc>, end: DateTime<Utc>) -> io::Result<()> {
+
+        if let Some(prev) = self.last.get(entity) {
+            if begin < prev.end {
+                let msg = format!("operations overlap on {} at {}: {} on {} \
+                    ({} to {}) and {} on {} ({} to {})", entity,
+                    begin.to_rfc3339(), prev.api, prev.host,
+                    prev.begin.to_rfc3339(), prev.end.to_rfc3339(), api, host,
+                    begin.to_rfc3339(), end.to_rfc3339());
+
+                if self.serial {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("{}; the workload is not serial", msg)));
+                }
+
+                let seen = self.count.fetch_add(1, Ordering::Relaxed);
+                if seen < MAX_REPORTED {
+                    eprintln!("minio-statemap: {}", msg);
+                }
+            }
+
+            if end < prev.end {
+                return Ok(());
+            }
+        }
+
+        self.last.insert(entity.to_string(), Operation {
+            host: host.to_string(),
+            api: api.to_string(),
+            begin,
+            end,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::at;
+
+    fn overlaps(ops: &[(&str, i64, i64)]) -> u64 {
+        let count = Arc::new(AtomicU64::new(0));
+        let mut overlaps = Overlaps::new(false, Arc::clone(&count));
+        for (entity, begin, end) in ops {
+            overlaps.check(entity, "h1", "s3.GetObject", at(*begin), at(*end))
+                .unwrap();
+        }
+        count.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn allows_serial_operations() {
+        assert_eq!(overlaps(&[("e", 0, 10), ("e", 10, 20), ("e", 25, 30)]),
+            0);
+        assert_eq!(overlaps(&[("e", 0, 10), ("f", 5, 20)]), 0);
+    }
+
+    #[test]
+    fn counts_overlaps() {
+        assert_eq!(overlaps(&[("e", 0, 10), ("e", 5, 20), ("e", 15, 30)]),
+            2);
+    }
+
+    #[test]
+    fn remembers_longest_operation() {
+        /*
+         * The third operation arrives out of end time order, nested in the
+         * second. The fourth still overlaps the second.
+         */
+        assert_eq!(overlaps(&[("e", 10, 20), ("e", 0, 100), ("e", 50, 60),
+            ("e", 70, 110)]), 3);
+    }
+
+    #[test]
+    fn fails_if_serial() {
+        let mut overlaps = Overlaps::new(true, Arc::new(AtomicU64::new(0)));
+        overlaps.check("e", "h1", "s3.GetObject", at(0), at(10)).unwrap();
+        let err = overlaps.check("e", "h2", "s3.PutObject", at(5), at(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("s3.PutObject on h2"));
+    }
+}
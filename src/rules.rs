@@ -0,0 +1,323 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use chrono::Duration;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::trace::TraceData;
+use crate::window::parse_duration;
+
+/*
+ * The rules file, in TOML or JSON:
+ *
+ *     [[rule]]
+ *     api = '^internal\..*Vol$'
+ *     state = 'volume ops'
+ *     color = '#9999ff'
+ *
+ *     [[rule]]
+ *     api = '^s3\.'
+ *     method = 'PUT'
+ *     min_duration = '500ms'
+ *     state = 'slow S3'
+ *     color = 'red'
+ *
+ *     [colors]
+ *     's3.GetObject' = 'green'
+ */
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default, alias = "rules")]
+    rule: Vec<RuleSpec>,
+    #[serde(default)]
+    colors: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RuleSpec {
+    api: Option<String>,
+    method: Option<String>,
+    status: Option<StatusSpec>,
+    min_duration: Option<String>,
+    max_duration: Option<String>,
+    state: Option<String>,
+    color: Option<String>,
+}
+
+/* A status code, such as 503, or a class of them, such as '5xx'. */
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusSpec {
+    Code(u32),
+    Pattern(String),
+}
+
+struct Rule {
+    api: Option<Regex>,
+    method: Option<String>,
+    status: Option<(u32, u32)>,
+    min_duration: Option<Duration>,
+    max_duration: Option<Duration>,
+    state: Option<String>,
+}
+
+/*
+ * Rules decides what state each call is drawn in. By default that is just
+ * the API called, but on a large cluster that gives a legend too long to be
+ * useful, so rules can merge related APIs into one state or pick out calls
+ * by status or duration. The first rule to match a call wins.
+ *
+ * A rule's state may refer to groups captured by its API regex, e.g. '$1'.
+ * A rule without a state leaves matching calls as they are, so that later
+ * rules don't apply to them.
+ */
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    colors: BTreeMap<String, String>,
+}
+
+impl Rules {
+    /*
+     * Read a rules file. Files ending in '.json' are JSON and anything else
+     * is taken to be TOML.
+     */
+    pub fn load(path: &str) -> io::Result<Rules> {
+        let text = fs::read_to_string(path)?;
+        Rules::parse(path, &text)
+    }
+
+    fn parse(path: &str, text: &str) -> io::Result<Rules> {
+        let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidData,
+            format!("{}: {}", path, why));
+
+        let file: RulesFile = if path.ends_with(".json") {
+            serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?
+        } else {
+            toml::from_str(text).map_err(|e| invalid(e.to_string()))?
+        };
+
+        let mut rules = Rules {
+            rules: Vec::new(),
+            colors: file.colors,
+        };
+
+        for spec in file.rule {
+            let api = match &spec.api {
+                Some(a) => Some(Regex::new(a).map_err(|e| invalid(format!(
+                    "invalid api pattern '{}': {}", a, e)))?),
+                None => None,
+            };
+            let status = match &spec.status {
+                Some(s) => Some(status_range(s).ok_or_else(|| invalid(
+                    "invalid status: expected a code such as 503 or a class \
+                    such as '5xx'".to_string()))?),
+                None => None,
+            };
+            let duration = |d: &Option<String>| match d {
+                Some(d) => parse_duration(d).map(Some).ok_or_else(|| invalid(
+                    format!("invalid duration '{}'", d))),
+                None => Ok(None),
+            };
+
+            /*
+             * A rule's color is given to the state it names. States named
+             * from captured groups can be colored in the colors table.
+             */
+            if let Some(color) = &spec.color {
+                match &spec.state {
+                    Some(s) if !s.contains('$') => {
+                        rules.colors.entry(s.clone())
+                            .or_insert_with(|| color.clone());
+                    },
+                    _ => return Err(invalid(format!("color '{}' needs a rule \
+                        with a fixed state name", color))),
+                }
+            }
+
+            rules.rules.push(Rule {
+                api,
+                method: spec.method,
+                status,
+                min_duration: duration(&spec.min_duration)?,
+                max_duration: duration(&spec.max_duration)?,
+                state: spec.state,
+            });
+        }
+
+        Ok(rules)
+    }
+
+    /* The state a call is drawn in. */
+    pub fn state(&self, td: &TraceData) -> String {
+        let duration = Duration::nanoseconds(td.call_stats.duration as i64);
+
+        for rule in &self.rules {
+            /*
+             * Only verbose traces record the HTTP method, so rules that
+             * need one never match calls from other traces.
+             */
+            if let Some(method) = &rule.method {
+                let called = td.method();
+                if !called.is_some_and(|m| m.eq_ignore_ascii_case(method)) {
+                    continue;
+                }
+            }
+            if let Some((lo, hi)) = rule.status {
+                if td.status_code < lo || td.status_code > hi {
+                    continue;
+                }
+            }
+            if rule.min_duration.is_some_and(|min| duration < min) ||
+                rule.max_duration.is_some_and(|max| duration > max) {
+                continue;
+            }
+
+            let captures = match &rule.api {
+                Some(re) => match re.captures(&td.api) {
+                    Some(c) => Some(c),
+                    None => continue,
+                },
+                None => None,
+            };
+
+            return match (&rule.state, captures) {
+                (None, _) => td.api.clone(),
+                (Some(state), None) => state.clone(),
+                (Some(state), Some(c)) => {
+                    let mut expanded = String::new();
+                    c.expand(state, &mut expanded);
+                    expanded
+                },
+            };
+        }
+
+        td.api.clone()
+    }
+
+    /* The states that have been given colors, and their colors. */
+    pub fn colors(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.colors.iter()
+    }
+}
+
+fn status_range(spec: &StatusSpec) -> Option<(u32, u32)> {
+    match spec {
+        StatusSpec::Code(c) => Some((*c, *c)),
+        StatusSpec::Pattern(p) => {
+            if let Ok(c) = p.parse() {
+                return Some((c, c));
+            }
+            let class = match p.strip_suffix("xx")? {
+                c @ ("1" | "2" | "3" | "4" | "5") => c.parse::<u32>().ok()?,
+                _ => return None,
+            };
+            Some((class * 100, class * 100 + 99))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::HttpDetails;
+    use crate::trace::testing::call;
+
+    const RULES: &str = r#"
+        [[rule]]
+        status = '5xx'
+        state = 'server errors'
+
+        [[rule]]
+        api = '^s3\.'
+        method = 'put'
+        state = 'writes'
+
+        [[rule]]
+        api = '^internal\.(.*)$'
+        min_duration = '10ms'
+        state = 'slow $1'
+    "#;
+
+    fn verbose(mut td: TraceData, method: &str) -> TraceData {
+        td.http = Some(HttpDetails {
+            method: method.to_string(),
+            proto: "HTTP/1.1".to_string(),
+            request_headers: BTreeMap::new(),
+            response_headers: BTreeMap::new(),
+        });
+        td
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = Rules::parse("rules.toml", RULES).unwrap();
+
+        let mut failed = verbose(call("s3.PutObject", "h1", 0, 1), "PUT");
+        failed.status_code = 503;
+        assert_eq!(rules.state(&failed), "server errors");
+
+        let put = verbose(call("s3.PutObject", "h1", 0, 1), "PUT");
+        assert_eq!(rules.state(&put), "writes");
+        let get = verbose(call("s3.GetObject", "h1", 0, 1), "GET");
+        assert_eq!(rules.state(&get), "s3.GetObject");
+
+        /* Without a verbose trace there is no method to match. */
+        let put = call("s3.PutObject", "h1", 0, 1);
+        assert_eq!(rules.state(&put), "s3.PutObject");
+    }
+
+    #[test]
+    fn expands_captures() {
+        let rules = Rules::parse("rules.toml", RULES).unwrap();
+
+        let slow = call("internal.ReadFile", "h1", 0, 20);
+        assert_eq!(rules.state(&slow), "slow ReadFile");
+        let fast = call("internal.ReadFile", "h1", 0, 5);
+        assert_eq!(rules.state(&fast), "internal.ReadFile");
+    }
+
+    #[test]
+    fn colors_need_a_fixed_state() {
+        let rules = Rules::parse("rules.json", r#"{
+            "rules": [{"api": "^s3", "state": "S3", "color": "red"}],
+            "colors": {"waiting": "grey"}
+        }"#).unwrap();
+        let colors: Vec<(&String, &String)> = rules.colors().collect();
+        assert_eq!(colors.len(), 2);
+
+        assert!(Rules::parse("rules.toml", r#"
+            [[rule]]
+            api = '^(s3)'
+            state = '$1'
+            color = 'red'
+        "#).is_err());
+    }
+
+    #[test]
+    fn parses_status_classes() {
+        let pattern = |p: &str| status_range(&StatusSpec::Pattern(
+            p.to_string()));
+        assert_eq!(pattern("4xx"), Some((400, 499)));
+        assert_eq!(pattern("503"), Some((503, 503)));
+        for p in &["0xx", "6xx", "+5xx", "99999999xx", "xx", "5x"] {
+            assert_eq!(pattern(p), None, "{}", p);
+        }
+
+        assert!(Rules::parse("rules.toml", r#"
+            [[rule]]
+            status = '99999999xx'
+            state = 'odd'
+        "#).is_err());
+    }
+}
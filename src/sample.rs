@@ -0,0 +1,250 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::VecDeque;
+use std::io;
+use std::str::FromStr;
+
+use crate::trace::TraceData;
+
+/*
+ * How to choose which S3 requests to keep. Each mode keeps one in every N.
+ */
+#[derive(Clone, Copy)]
+pub enum SampleMode {
+    /* Every Nth S3 request, in the order they finish. */
+    Every(u64),
+    /* Requests whose object path hashes to a multiple of N. */
+    Path(u64),
+}
+
+impl FromStr for SampleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SampleMode, String> {
+        let err = || format!("invalid sample mode '{}': expected 'every:N' \
+            or 'path:N'", s);
+
+        let mut parts = s.splitn(2, ':');
+        let mode = parts.next().ok_or_else(err)?;
+        let n: u64 = parts.next()
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n > 0)
+            .ok_or_else(err)?;
+
+        match mode {
+            "every" => Ok(SampleMode::Every(n)),
+            "path" => Ok(SampleMode::Path(n)),
+            _ => Err(err()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Decision {
+    Pending,
+    Keep,
+    Drop,
+}
+
+/*
+ * Sample thins out a stream of correlated trace records (see correlate.rs)
+ * while keeping each retained S3 request complete: the calls made for a
+ * request (on any host) are kept or dropped along with it. Calls that no S3
+ * request accounts for are sampled on their own.
+ *
+ * A request is reported after the calls made for it, so those calls are
+ * queued until it arrives. This keeps the output in the same order as the
+ * input.
+ */
+pub struct Sample<I> {
+    records: I,
+    mode: SampleMode,
+    /*
+     * How many S3 requests, and calls no request accounted for, have been
+     * sampled. They're counted apart so that the requests kept don't depend
+     * on how many other calls there were.
+     */
+    requests: u64,
+    orphans: u64,
+    queue: VecDeque<(TraceData, Decision)>,
+    done: bool,
+}
+
+impl<I> Sample<I> where I: Iterator<Item = io::Result<TraceData>> {
+    pub fn new(records: I, mode: SampleMode) -> Sample<I> {
+        Sample {
+            records,
+            mode,
+            requests: 0,
+            orphans: 0,
+            queue: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn add(&mut self, td: TraceData) {
+        if td.trace_type != "s3" {
+            let decision = match td.request {
+                Some(_) => Decision::Pending,
+                None => decide(self.mode, &mut self.orphans, &td.path),
+            };
+            self.queue.push_back((td, decision));
+            return;
+        }
+
+        let decision = decide(self.mode, &mut self.requests, &td.path);
+        for (call, d) in self.queue.iter_mut() {
+            if *d == Decision::Pending && call.request == td.request {
+                *d = decision;
+            }
+        }
+
+        self.queue.push_back((td, decision));
+    }
+
+    /*
+     * Once the input is exhausted, any calls still waiting for their request
+     * never will see it, so sample them on their own.
+     */
+    fn finish(&mut self) {
+        for (call, d) in self.queue.iter_mut() {
+            if *d == Decision::Pending {
+                *d = decide(self.mode, &mut self.orphans, &call.path);
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Sample<I>
+    where I: Iterator<Item = io::Result<TraceData>> {
+
+    type Item = io::Result<TraceData>;
+
+    fn next(&mut self) -> Option<io::Result<TraceData>> {
+        loop {
+            while let Some((_, d)) = self.queue.front() {
+                match d {
+                    Decision::Pending => break,
+                    Decision::Drop => {
+                        self.queue.pop_front();
+                    },
+                    Decision::Keep => {
+                        return self.queue.pop_front().map(|(td, _)| Ok(td));
+                    },
+                }
+            }
+
+            if self.done {
+                return None;
+            }
+
+            match self.records.next() {
+                Some(Ok(td)) => self.add(td),
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.done = true;
+                    self.finish();
+                },
+            }
+        }
+    }
+}
+
+/*
+ * Decide whether to keep a record, given how many like it have been seen.
+ */
+fn decide(mode: SampleMode, seen: &mut u64, path: &str) -> Decision {
+    let keep = match mode {
+        SampleMode::Every(n) => {
+            *seen += 1;
+            (*seen - 1).is_multiple_of(n)
+        },
+        SampleMode::Path(n) => fnv1a(path.as_bytes()).is_multiple_of(n),
+    };
+
+    if keep {
+        Decision::Keep
+    } else {
+        Decision::Drop
+    }
+}
+
+/*
+ * FNV-1a, used for path sampling because, unlike std's hasher, its output is
+ * the same on every run and every platform.
+ */
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlate::Correlate;
+    use crate::trace::testing::{call, verbose};
+
+    fn sample(records: Vec<TraceData>, mode: SampleMode) -> Vec<String> {
+        Sample::new(Correlate::new(records.into_iter().map(Ok)), mode)
+            .map(|r| r.unwrap().api)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_requests_with_their_calls() {
+        let records = vec![
+            call("storage.ReadFile", "h2", 10, 20),
+            call("s3.GetObject", "h1", 0, 30),
+            call("storage.ReadFile", "h2", 40, 50),
+            call("s3.HeadObject", "h1", 35, 60),
+            call("storage.WriteAll", "h2", 70, 80),
+            call("s3.PutObject", "h1", 65, 90),
+        ];
+        assert_eq!(sample(records, SampleMode::Every(2)),
+            vec!["storage.ReadFile", "s3.GetObject", "storage.WriteAll",
+            "s3.PutObject"]);
+    }
+
+    #[test]
+    fn keeps_calls_matched_by_request_id() {
+        /*
+         * The first call's host has a clock that's behind, so it appears to
+         * have been made before the request began.
+         */
+        let records = vec![
+            verbose(call("storage.ReadFile", "h2", 0, 5), Some("A"), None),
+            verbose(call("storage.ReadFile", "h2", 12, 18), Some("B"), None),
+            verbose(call("s3.GetObject", "h1", 10, 20), None, Some("A")),
+            verbose(call("s3.GetObject", "h1", 11, 25), None, Some("B")),
+        ];
+        assert_eq!(sample(records, SampleMode::Every(2)),
+            vec!["storage.ReadFile", "s3.GetObject"]);
+    }
+
+    #[test]
+    fn orphans_do_not_shift_requests() {
+        /*
+         * Calls that no request accounts for are sampled separately, so
+         * they don't change which requests are kept, even when they're
+         * sampled in between them.
+         */
+        let records = vec![
+            call("s3.GetObject", "h1", 0, 10),
+            call("internal.ServerInfo", "h2", 15, 20),
+            call("s3.HeadObject", "h1", 70_000, 70_010),
+            call("s3.PutObject", "h1", 70_020, 70_030),
+        ];
+        assert_eq!(sample(records, SampleMode::Every(2)),
+            vec!["s3.GetObject", "internal.ServerInfo", "s3.PutObject"]);
+    }
+}
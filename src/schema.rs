@@ -0,0 +1,289 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+/*
+ * The JSON formats MinIO has used for trace records over time. Each is
+ * converted into a TraceData, so the rest of the program doesn't need to know
+ * which one a capture was written in.
+ */
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::trace::{CallStats, HttpDetails, TraceData};
+
+/*
+ * LegacyTrace represents the original non-verbose `mc admin trace --json`
+ * format.
+ */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyTrace {
+    host: String,
+    time: DateTime<Utc>,
+    client: String,
+    call_stats: LegacyCallStats,
+    api: String,
+    path: String,
+    query: String,
+    status_code: u32,
+    status_msg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LegacyCallStats {
+    rx: u64,
+    tx: u64,
+    duration: u64,
+    time_to_first_byte: u64,
+}
+
+impl From<LegacyTrace> for TraceData {
+    fn from(l: LegacyTrace) -> TraceData {
+        TraceData {
+            trace_type: trace_type_from_api(&l.api),
+            host: l.host,
+            time: l.time,
+            client: l.client,
+            call_stats: CallStats {
+                rx: l.call_stats.rx,
+                tx: l.call_stats.tx,
+                duration: l.call_stats.duration,
+                time_to_first_byte: l.call_stats.time_to_first_byte,
+            },
+            api: l.api,
+            path: l.path,
+            query: l.query,
+            status_code: l.status_code,
+            status_msg: l.status_msg,
+            error: String::new(),
+            http: None,
+            request: None,
+        }
+    }
+}
+
+/*
+ * LegacyVerboseTrace represents the original `mc admin trace -v --json`
+ * format. Different versions of mc disagree on some field names, so aliases
+ * are used to accept both.
+ */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyVerboseTrace {
+    #[serde(alias = "nodename")]
+    node_name: String,
+    #[serde(alias = "funcname")]
+    func_name: String,
+    #[serde(alias = "request")]
+    req_info: RequestInfo,
+    #[serde(alias = "response")]
+    resp_info: ResponseInfo,
+    #[serde(alias = "stats")]
+    call_stats: HttpCallStats,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestInfo {
+    #[serde(default)]
+    proto: String,
+    method: String,
+    #[serde(default)]
+    path: String,
+    #[serde(default, alias = "rawquery")]
+    raw_query: String,
+    #[serde(default)]
+    headers: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    client: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseInfo {
+    time: DateTime<Utc>,
+    #[serde(default)]
+    headers: BTreeMap<String, Vec<String>>,
+    #[serde(default, alias = "statuscode")]
+    status_code: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpCallStats {
+    #[serde(default, alias = "inputbytes")]
+    input_bytes: u64,
+    #[serde(default, alias = "outputbytes")]
+    output_bytes: u64,
+    #[serde(default)]
+    latency: u64,
+    #[serde(default, alias = "timetofirstbyte")]
+    time_to_first_byte: u64,
+}
+
+impl From<LegacyVerboseTrace> for TraceData {
+    fn from(v: LegacyVerboseTrace) -> TraceData {
+        TraceData {
+            trace_type: trace_type_from_api(&v.func_name),
+            host: v.node_name,
+            /*
+             * As with the non-verbose format, the record's time is when the
+             * response was sent.
+             */
+            time: v.resp_info.time,
+            client: v.req_info.client,
+            call_stats: CallStats {
+                rx: v.call_stats.input_bytes,
+                tx: v.call_stats.output_bytes,
+                duration: v.call_stats.latency,
+                time_to_first_byte: v.call_stats.time_to_first_byte,
+            },
+            api: v.func_name,
+            path: v.req_info.path,
+            query: v.req_info.raw_query,
+            status_code: v.resp_info.status_code,
+            status_msg: String::new(),
+            error: String::new(),
+            http: Some(HttpDetails {
+                method: v.req_info.method,
+                proto: v.req_info.proto,
+                request_headers: v.req_info.headers,
+                response_headers: v.resp_info.headers,
+            }),
+            request: None,
+        }
+    }
+}
+
+/*
+ * TraceInfo represents the format emitted by current MinIO servers, which
+ * covers storage, OS and internal traces as well as S3 calls. Only S3 and
+ * internal RPC traces carry the nested 'http' object.
+ *
+ * Unlike the legacy formats, 'time' is when the operation _started_.
+ */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceInfo {
+    #[serde(default, alias = "type")]
+    trace_type: Value,
+    #[serde(alias = "nodename")]
+    node_name: String,
+    #[serde(alias = "funcname")]
+    func_name: String,
+    time: DateTime<Utc>,
+    #[serde(default)]
+    path: String,
+    #[serde(default, alias = "dur")]
+    duration: u64,
+    #[serde(default)]
+    bytes: u64,
+    #[serde(default)]
+    error: String,
+    http: Option<TraceHttpStats>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceHttpStats {
+    #[serde(alias = "request")]
+    req_info: RequestInfo,
+    #[serde(alias = "response")]
+    resp_info: ResponseInfo,
+    #[serde(alias = "stats")]
+    call_stats: HttpCallStats,
+}
+
+impl From<TraceInfo> for TraceData {
+    fn from(t: TraceInfo) -> TraceData {
+        let trace_type = match &t.trace_type {
+            Value::Null => trace_type_from_api(&t.func_name),
+            tt => trace_type_name(tt),
+        };
+
+        let mut td = TraceData {
+            trace_type,
+            host: t.node_name,
+            time: t.time + Duration::nanoseconds(t.duration as i64),
+            client: String::new(),
+            call_stats: CallStats {
+                rx: 0,
+                tx: t.bytes,
+                duration: t.duration,
+                time_to_first_byte: 0,
+            },
+            api: t.func_name,
+            path: t.path,
+            query: String::new(),
+            status_code: 0,
+            status_msg: String::new(),
+            error: t.error,
+            http: None,
+            request: None,
+        };
+
+        if let Some(http) = t.http {
+            td.client = http.req_info.client;
+            td.query = http.req_info.raw_query;
+            td.status_code = http.resp_info.status_code;
+            td.call_stats.rx = http.call_stats.input_bytes;
+            td.call_stats.tx = http.call_stats.output_bytes;
+            td.call_stats.time_to_first_byte =
+                http.call_stats.time_to_first_byte;
+            if td.path.is_empty() {
+                td.path = http.req_info.path;
+            }
+            td.http = Some(HttpDetails {
+                method: http.req_info.method,
+                proto: http.req_info.proto,
+                request_headers: http.req_info.headers,
+                response_headers: http.resp_info.headers,
+            });
+        }
+
+        td
+    }
+}
+
+/*
+ * Legacy formats have no trace type, but the API name is prefixed with it,
+ * e.g. 's3.PutObject' or 'internal.ReadFile'.
+ */
+fn trace_type_from_api(api: &str) -> String {
+    match api.find('.') {
+        Some(i) => api[..i].to_lowercase(),
+        None => String::new(),
+    }
+}
+
+/*
+ * The trace type is a bitmask in the server's own JSON and a name in some
+ * versions of mc.
+ */
+fn trace_type_name(tt: &Value) -> String {
+    match tt {
+        Value::String(s) => s.to_lowercase(),
+        Value::Number(n) => match n.as_u64() {
+            Some(1) => "os".to_string(),
+            Some(2) => "storage".to_string(),
+            Some(4) => "s3".to_string(),
+            Some(8) => "internal".to_string(),
+            Some(16) => "scanner".to_string(),
+            Some(32) => "decommission".to_string(),
+            Some(64) => "healing".to_string(),
+            _ => format!("type{}", n),
+        },
+        _ => String::new(),
+    }
+}
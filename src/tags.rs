@@ -0,0 +1,87 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::io::{self, Write};
+
+use serde_json::{json, Value};
+
+use crate::trace::TraceData;
+
+/*
+ * Tags records the details of each call so that they can be shown for the
+ * block it's drawn as in the rendered statemap.
+ *
+ * A statemap tag belongs to one state and is defined by a record of its own,
+ * separate from the state records that use it. The statemap crate only
+ * passes tags through, so the definitions are kept here until the statemap
+ * is printed.
+ */
+#[derive(Default)]
+pub struct Tags {
+    next: u64,
+    /* Tags not yet printed: their state, name and details. */
+    pending: Vec<(String, String, Value)>,
+}
+
+impl Tags {
+    /*
+     * Make a tag for a call drawn in 'state' and return its name.
+     */
+    pub fn tag(&mut self, state: &str, td: &TraceData) -> String {
+        let name = self.next.to_string();
+        self.next += 1;
+
+        let ms = |ns: u64| ns as f64 / 1e6;
+        let mut details = json!({
+            "api": td.api,
+            "host": td.host,
+            "client": td.client,
+            "path": td.path,
+            "query": td.query,
+            "status": td.status_code,
+            "rx": td.call_stats.rx,
+            "tx": td.call_stats.tx,
+            "duration_ms": ms(td.call_stats.duration),
+            "ttfb_ms": ms(td.call_stats.time_to_first_byte),
+        });
+        if !td.error.is_empty() {
+            details["error"] = json!(td.error);
+        }
+        if let Some(id) = td.request_id() {
+            details["request_id"] = json!(id);
+        }
+        if let Some(http) = &td.http {
+            details["method"] = json!(http.method);
+            details["proto"] = json!(http.proto);
+        }
+
+        self.pending.push((state.to_string(), name.clone(), details));
+        name
+    }
+
+    /*
+     * Print the definitions of the tags made since the last call. 'states'
+     * is the statemap metadata's map of state names to their descriptions,
+     * which give the value each state is known by.
+     */
+    pub fn print(&mut self, states: &Value, out: &mut dyn Write)
+        -> io::Result<()> {
+
+        for (state, name, mut details) in self.pending.drain(..) {
+            let value = match states[&state]["value"].as_u64() {
+                Some(v) => v,
+                None => continue,
+            };
+            details["state"] = json!(value);
+            details["tag"] = json!(name);
+            writeln!(out, "{}", details)?;
+        }
+
+        Ok(())
+    }
+}
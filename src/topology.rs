@@ -0,0 +1,334 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use url::Url;
+
+use crate::alias::Aliases;
+use crate::drive::Drives;
+use crate::entity::EntityKind;
+use crate::trace::TraceData;
+
+/* The port MinIO listens on when an endpoint doesn't give one. */
+const DEFAULT_PORT: u16 = 9000;
+
+/* The erasure set sizes MinIO chooses from, largest first. */
+const SET_SIZES: [usize; 15] = [16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4,
+    3, 2];
+
+/*
+ * Topology describes how a cluster's drives are laid out into server pools
+ * and erasure sets, so that entities can be grouped the same way. It is built
+ * from the same endpoint arguments given to `minio server`, e.g.
+ *
+ *     http://minio{1...4}/data{1...4}
+ *
+ * Each pattern is one pool. As in MinIO, its drives are divided into equal
+ * erasure sets in the order the pattern expands, with the leftmost range
+ * varying fastest so that each set is spread across the servers. Unless
+ * given one, the set size is chosen as MinIO chooses it.
+ */
+#[derive(Default)]
+pub struct Topology {
+    /* (server, drive path) => (pool, set), both counted from 1. */
+    drives: HashMap<(String, String), (usize, usize)>,
+    /* server => the first pool it appears in. */
+    hosts: HashMap<String, usize>,
+    pools: usize,
+    max_sets: usize,
+}
+
+impl Topology {
+    /*
+     * Add a server pool. 'set_size' overrides the erasure set size MinIO
+     * would choose for it.
+     */
+    pub fn add_pool(&mut self, pattern: &str, set_size: Option<usize>)
+        -> Result<(), String> {
+
+        let (literals, ranges) = parse_pattern(pattern)?;
+        let mut endpoints = Vec::new();
+        for endpoint in expand(&literals, &ranges) {
+            endpoints.push(parse_endpoint(&endpoint)?);
+        }
+
+        let leftmost = ranges.first().map(|r| r.len());
+        let set_size = match set_size {
+            Some(n) if n > 0 && endpoints.len() % n == 0 => n,
+            Some(n) => return Err(format!("{} drives in '{}' can't be \
+                divided into sets of {}", endpoints.len(), pattern, n)),
+            None => default_set_size(endpoints.len(), leftmost)
+                .ok_or_else(|| format!("{} drives in '{}' can't be divided \
+                    into erasure sets", endpoints.len(), pattern))?,
+        };
+
+        self.pools += 1;
+        let pool = self.pools;
+        self.max_sets = self.max_sets.max(endpoints.len() / set_size);
+
+        for (i, (host, drive)) in endpoints.into_iter().enumerate() {
+            self.hosts.entry(host.clone()).or_insert(pool);
+            self.drives.insert((host, drive), (pool, i / set_size + 1));
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Read a topology file. Each line is a pool, given as an endpoint pattern
+     * optionally followed by its erasure set size. Blank lines and '#'
+     * comments are ignored.
+     *
+     *     http://minio{1...4}/data{1...4}
+     *     http://minio{5...8}/data{1...8}  8
+     */
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |why: String| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} line {}: {}", path, n + 1, why));
+
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next().unwrap_or_default();
+            let set_size = match fields.next() {
+                None => None,
+                Some(s) => Some(s.parse().map_err(|_| invalid(format!(
+                    "invalid erasure set size '{}'", s)))?),
+            };
+
+            self.add_pool(pattern, set_size).map_err(invalid)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools == 0
+    }
+
+    /* Tell 'drives' where each server's drives are mounted. */
+    pub fn add_mounts(&self, drives: &mut Drives) {
+        for (host, mount) in self.drives.keys() {
+            drives.add(host, mount);
+        }
+    }
+
+    /*
+     * Servers are named in trace records as 'host:port', which aliases may
+     * have renamed. Rename them here too so the two still match.
+     */
+    pub fn rename(&mut self, aliases: &Aliases) {
+        self.hosts = self.hosts.drain()
+            .map(|(h, pool)| (aliases.name(&h), pool))
+            .collect();
+        self.drives = self.drives.drain()
+            .map(|((h, d), place)| ((aliases.name(&h), d), place))
+            .collect();
+    }
+
+    /*
+     * Where an entity sits in the cluster: its pool for a server, e.g.
+     * 'pool1', or its pool and erasure set for a drive, e.g. 'pool1/set03'.
+     */
+    pub fn group(&self, kind: EntityKind, td: &TraceData, drives: &Drives)
+        -> Option<String> {
+
+        match kind {
+            EntityKind::Host => self.hosts.get(&td.host)
+                .map(|pool| format!("pool{}", pool)),
+            EntityKind::Drive => {
+                let d = drives.drive(td)?;
+                let (pool, set) = self.drives.get(&(td.host.clone(), d))?;
+                Some(format!("pool{}/set{:0w$}", pool, set,
+                    w = digits(self.max_sets)))
+            },
+            _ => None,
+        }
+    }
+
+    /*
+     * Prefix an entity with where it sits in the cluster. Entities the
+     * topology doesn't cover are left alone.
+     */
+    pub fn place(&self, kind: EntityKind, td: &TraceData, drives: &Drives,
+        entity: String) -> String {
+
+        match self.group(kind, td, drives) {
+            Some(group) => format!("{} {}", group, entity),
+            None => entity,
+        }
+    }
+}
+
+fn digits(n: usize) -> usize {
+    n.to_string().len()
+}
+
+/*
+ * The erasure set size MinIO chooses for a pool of 'drives' drives whose
+ * leftmost range, if it has any, has 'leftmost' values. It's the largest
+ * size that divides the drives evenly and is symmetric with that range: one
+ * of the two must be a multiple of the other. So 9 servers of 5 drives each
+ * make 5 sets of 9, not 3 of 15.
+ */
+fn default_set_size(drives: usize, leftmost: Option<usize>)
+    -> Option<usize> {
+
+    let symmetric = |n: usize| match leftmost {
+        Some(l) if l > n => l.is_multiple_of(n),
+        Some(l) => n.is_multiple_of(l),
+        None => true,
+    };
+
+    SET_SIZES.iter().copied()
+        .find(|n| drives.is_multiple_of(*n) && symmetric(*n))
+}
+
+/*
+ * Parse MinIO's ellipsis syntax into the literal text of a pattern and the
+ * ranges between it: each '{a...b}' is the numbers from a to b, keeping any
+ * leading zeros.
+ */
+fn parse_pattern(pattern: &str)
+    -> Result<(Vec<&str>, Vec<Vec<String>>), String> {
+
+    let invalid = || format!("invalid endpoint pattern '{}'", pattern);
+
+    /* Split the pattern into literal text and the ranges between it. */
+    let mut literals = Vec::new();
+    let mut ranges: Vec<Vec<String>> = Vec::new();
+    let mut rest = pattern;
+
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').ok_or_else(invalid)? + open;
+        let mut bounds = rest[open + 1..close].splitn(2, "...");
+        let (lo, hi) = match (bounds.next(), bounds.next()) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _ => return Err(invalid()),
+        };
+        let width = if lo.starts_with('0') { lo.len() } else { 0 };
+        let lo: u64 = lo.parse().map_err(|_| invalid())?;
+        let hi: u64 = hi.parse().map_err(|_| invalid())?;
+        if lo > hi {
+            return Err(invalid());
+        }
+
+        literals.push(&rest[..open]);
+        ranges.push((lo..=hi).map(|n| format!("{:0w$}", n, w = width))
+            .collect());
+        rest = &rest[close + 1..];
+    }
+    literals.push(rest);
+
+    Ok((literals, ranges))
+}
+
+/*
+ * Combine the literals and ranges of a pattern into every string it stands
+ * for, with the leftmost range varying fastest.
+ */
+fn expand(literals: &[&str], ranges: &[Vec<String>]) -> Vec<String> {
+    let total: usize = ranges.iter().map(|r| r.len()).product();
+    let mut expanded = Vec::with_capacity(total);
+
+    for mut i in 0..total {
+        let mut s = literals[0].to_string();
+        for (range, literal) in ranges.iter().zip(&literals[1..]) {
+            s.push_str(&range[i % range.len()]);
+            s.push_str(literal);
+            i /= range.len();
+        }
+        expanded.push(s);
+    }
+
+    expanded
+}
+
+/*
+ * Split an endpoint URL into the server, named as trace records name it,
+ * and the drive path.
+ */
+fn parse_endpoint(endpoint: &str) -> Result<(String, String), String> {
+    let url = Url::parse(endpoint)
+        .map_err(|e| format!("invalid endpoint '{}': {}", endpoint, e))?;
+    let host = url.host_str()
+        .ok_or_else(|| format!("endpoint '{}' has no host", endpoint))?;
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+
+    Ok((format!("{}:{}", host, port), url.path().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(pattern: &str) -> Vec<String> {
+        let (literals, ranges) = parse_pattern(pattern).unwrap();
+        expand(&literals, &ranges)
+    }
+
+    #[test]
+    fn expands_leftmost_range_fastest() {
+        assert_eq!(expanded("http://m{1...3}/d{1...2}"), vec![
+            "http://m1/d1", "http://m2/d1", "http://m3/d1",
+            "http://m1/d2", "http://m2/d2", "http://m3/d2",
+        ]);
+    }
+
+    #[test]
+    fn expands_with_leading_zeros() {
+        assert_eq!(expanded("/data{08...10}"),
+            vec!["/data08", "/data09", "/data10"]);
+        assert_eq!(expanded("http://minio/data"), vec!["http://minio/data"]);
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in &["m{1...", "m{1..3}", "m{3...1}", "m{a...c}"] {
+            assert!(parse_pattern(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn chooses_symmetric_set_size() {
+        assert_eq!(default_set_size(16, Some(4)), Some(16));
+        assert_eq!(default_set_size(45, Some(9)), Some(9));
+        assert_eq!(default_set_size(45, Some(5)), Some(15));
+        assert_eq!(default_set_size(24, Some(3)), Some(12));
+        assert_eq!(default_set_size(32, None), Some(16));
+        assert_eq!(default_set_size(17, Some(17)), None);
+    }
+
+    #[test]
+    fn divides_pool_into_sets() {
+        let mut topology = Topology::default();
+        topology.add_pool("http://m{1...9}/d{1...5}", None).unwrap();
+
+        let place = |host: &str, drive: &str| {
+            topology.drives[&(host.to_string(), drive.to_string())]
+        };
+        assert_eq!(place("m1:9000", "/d1"), (1, 1));
+        assert_eq!(place("m9:9000", "/d1"), (1, 1));
+        assert_eq!(place("m1:9000", "/d2"), (1, 2));
+        assert_eq!(place("m9:9000", "/d5"), (1, 5));
+        assert_eq!(topology.max_sets, 5);
+
+        assert!(topology.add_pool("http://m{1...9}/d{1...5}", Some(4))
+            .is_err());
+    }
+}
@@ -0,0 +1,567 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::Value;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::frame::{Frame, Framer};
+use crate::schema::{LegacyTrace, LegacyVerboseTrace, TraceInfo};
+
+/*
+ * TraceData is a single MinIO trace record. Every trace format MinIO has used
+ * is normalised into a TraceData (see schema.rs) so the rest of the program
+ * only deals with one record type.
+ *
+ * 'time' is when the operation ended, and 'call_stats.duration' is how long it
+ * took in nanoseconds.
+ */
+#[derive(Clone, PartialEq)]
+#[allow(dead_code)]
+pub struct TraceData {
+    pub trace_type: String,
+    pub host: String,
+    pub time: DateTime<Utc>,
+    pub client: String,
+    pub call_stats: CallStats,
+    pub api: String,
+    pub path: String,
+    pub query: String,
+    pub status_code: u32,
+    pub status_msg: String,
+    pub error: String,
+    pub http: Option<HttpDetails>,
+    /*
+     * The S3 request this call was made on behalf of, once correlated (see
+     * correlate.rs). S3 calls are their own request.
+     */
+    pub request: Option<String>,
+}
+
+#[derive(Clone, PartialEq)]
+#[allow(dead_code)]
+pub struct CallStats {
+    pub rx: u64,
+    pub tx: u64,
+    pub duration: u64,
+    pub time_to_first_byte: u64,
+}
+
+impl TraceData {
+    /*
+     * MinIO's trace data is sorted by _end_ time of operation, not _start_
+     * time, and older versions of MinIO don't report the start time of each
+     * operation at all. They only report the end time of each operation and
+     * the duration of the operation, so we must infer the start time based on
+     * this information.
+     */
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.time - Duration::nanoseconds(self.call_stats.duration as i64)
+    }
+
+    /*
+     * When the call sent the first byte of its response, if that was
+     * recorded and came before the call ended.
+     */
+    pub fn first_byte(&self) -> Option<DateTime<Utc>> {
+        let ttfb = self.call_stats.time_to_first_byte;
+        if ttfb == 0 || ttfb >= self.call_stats.duration {
+            return None;
+        }
+        Some(self.start_time() + Duration::nanoseconds(ttfb as i64))
+    }
+
+    /*
+     * What went wrong with the call, if it failed: its HTTP status code if
+     * that was an error, or just 'error' if the record names one.
+     */
+    pub fn failure(&self) -> Option<String> {
+        if self.status_code >= 400 {
+            Some(self.status_code.to_string())
+        } else if !self.error.is_empty() {
+            Some("error".to_string())
+        } else {
+            None
+        }
+    }
+
+    /* The HTTP method of the call, if it was traced verbosely. */
+    pub fn method(&self) -> Option<&str> {
+        self.http.as_ref()
+            .map(|h| h.method.as_str())
+            .filter(|m| !m.is_empty())
+    }
+
+    /*
+     * The ID of the S3 request this call was made for, if it was traced
+     * verbosely. S3 calls return their own in their response headers. MinIO
+     * gives every response a fresh ID, internal ones included, so other
+     * calls only have one if the server that made them forwarded it in the
+     * request headers.
+     */
+    pub fn request_id(&self) -> Option<&str> {
+        let http = self.http.as_ref()?;
+        let headers = if self.trace_type == "s3" {
+            &http.response_headers
+        } else {
+            &http.request_headers
+        };
+
+        headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("x-amz-request-id"))
+            .and_then(|(_, v)| v.first())
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/*
+ * HttpDetails holds the parts of a verbose trace record that have no
+ * equivalent in the non-verbose format.
+ */
+#[derive(Clone, PartialEq)]
+pub struct HttpDetails {
+    pub method: String,
+    pub proto: String,
+    pub request_headers: BTreeMap<String, Vec<String>>,
+    pub response_headers: BTreeMap<String, Vec<String>>,
+}
+
+/* The trace formats we know how to read. See schema.rs. */
+#[derive(Clone, Copy)]
+pub enum Schema {
+    Legacy,
+    LegacyVerbose,
+    TraceInfo,
+}
+
+impl FromStr for Schema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Schema, String> {
+        match s {
+            "legacy" => Ok(Schema::Legacy),
+            "legacy-verbose" => Ok(Schema::LegacyVerbose),
+            "traceinfo" => Ok(Schema::TraceInfo),
+            _ => Err(format!("unknown trace schema '{}'", s)),
+        }
+    }
+}
+
+impl Schema {
+    /*
+     * Guess which schema a record was written in from the fields it has, or
+     * None if the record doesn't look like any of them.
+     */
+    fn detect(record: &Value) -> Option<Schema> {
+        let obj = record.as_object()?;
+        let has = |keys: &[&str]| keys.iter().any(|k| obj.contains_key(*k));
+
+        if has(&["callStats"]) && has(&["api"]) {
+            Some(Schema::Legacy)
+        } else if has(&["http", "dur", "duration", "traceType"]) {
+            Some(Schema::TraceInfo)
+        } else if has(&["request", "reqInfo"]) {
+            Some(Schema::LegacyVerbose)
+        } else {
+            None
+        }
+    }
+
+    fn parse(self, record: Value) -> serde_json::Result<TraceData> {
+        Ok(match self {
+            Schema::Legacy =>
+                serde_json::from_value::<LegacyTrace>(record)?.into(),
+            Schema::LegacyVerbose =>
+                serde_json::from_value::<LegacyVerboseTrace>(record)?.into(),
+            Schema::TraceInfo =>
+                serde_json::from_value::<TraceInfo>(record)?.into(),
+        })
+    }
+}
+
+/*
+ * How many records at the start of an input we'll look at while trying to
+ * work out its schema.
+ */
+const DETECT_RECORDS: usize = 16;
+
+/*
+ * Records pulls TraceData records from a reader one at a time so memory use
+ * does not grow with the size of the trace input.
+ *
+ * Unless a schema is given, the first records of the input are used to detect
+ * it. Those records are held in 'pending' until they can be parsed.
+ *
+ * Records that can't be parsed are reported on stderr, counted in 'skipped'
+ * and otherwise ignored. In strict mode the first one is returned as an error
+ * instead.
+ */
+pub struct Records {
+    name: String,
+    frames: Framer<BufReader<Box<dyn Read + Send>>>,
+    schema: Option<Schema>,
+    pending: VecDeque<(Frame, Value)>,
+    strict: bool,
+    skipped: Arc<AtomicU64>,
+}
+
+impl Records {
+    pub fn new(name: &str, input: Box<dyn Read + Send>, schema: Option<Schema>,
+        strict: bool, skipped: Arc<AtomicU64>) -> Records {
+
+        Records {
+            name: name.to_string(),
+            frames: Framer::new(BufReader::new(input)),
+            schema,
+            pending: VecDeque::new(),
+            strict,
+            skipped,
+        }
+    }
+
+    /*
+     * Deal with a record that couldn't be parsed. Returns an error if we're
+     * in strict mode, otherwise the record is reported and skipped.
+     */
+    fn malformed(&self, frame: &Frame, err: serde_json::Error)
+        -> io::Result<()> {
+
+        let msg = format!("malformed record at {} line {} (byte offset {}): \
+            {}", self.name, frame.line, frame.offset, err);
+
+        if self.strict {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        eprintln!("minio-statemap: skipping {}", msg);
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /*
+     * Read the next frame from the input that is at least valid JSON, or
+     * None at the end of the input.
+     */
+    fn next_value(&mut self) -> Option<io::Result<(Frame, Value)>> {
+        loop {
+            let frame = match self.frames.next()? {
+                Ok(f) => f,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match serde_json::from_slice::<Value>(&frame.text) {
+                Ok(v) => return Some(Ok((frame, v))),
+                Err(e) => {
+                    let recovered = recover(&frame);
+                    if let Err(e) = self.malformed(&frame, e) {
+                        return Some(Err(e));
+                    }
+                    if recovered.is_some() {
+                        return recovered.map(Ok);
+                    }
+                },
+            }
+        }
+    }
+
+    fn detect(&mut self) -> io::Result<Schema> {
+        while self.pending.len() < DETECT_RECORDS {
+            let (frame, record) = match self.next_value() {
+                Some(r) => r?,
+                None => break,
+            };
+
+            let schema = Schema::detect(&record);
+            self.pending.push_back((frame, record));
+
+            if let Some(s) = schema {
+                return Ok(s);
+            }
+        }
+
+        /*
+         * Nothing looked familiar. Fall back to the original format, which
+         * will at least tell the user what field it expected to find.
+         */
+        Ok(Schema::Legacy)
+    }
+}
+
+/*
+ * When a capture is appended to after `mc` was restarted, the record it was
+ * writing when it stopped can be followed on the same line by the first
+ * record of the new capture. The framer can't tell where one ends and the
+ * other begins, so it returns them as one frame. If a frame that isn't valid
+ * JSON ends in a complete record, return that record.
+ */
+fn recover(frame: &Frame) -> Option<(Frame, Value)> {
+    let text = &frame.text;
+
+    (1..text.len())
+        .filter(|&i| text[i] == b'{')
+        .find_map(|i| {
+            let value = serde_json::from_slice::<Value>(&text[i..]).ok()?;
+            let lines = text[..i].iter().filter(|&&b| b == b'\n').count();
+            let tail = Frame {
+                line: frame.line + lines as u64,
+                offset: frame.offset + i as u64,
+                text: text[i..].to_vec(),
+            };
+            Some((tail, value))
+        })
+}
+
+impl Iterator for Records {
+    type Item = io::Result<TraceData>;
+
+    fn next(&mut self) -> Option<io::Result<TraceData>> {
+        let schema = match self.schema {
+            Some(s) => s,
+            None => match self.detect() {
+                Ok(s) => {
+                    self.schema = Some(s);
+                    s
+                },
+                Err(e) => return Some(Err(e)),
+            },
+        };
+
+        loop {
+            let (frame, record) = match self.pending.pop_front() {
+                Some(r) => r,
+                None => match self.next_value()? {
+                    Ok(r) => r,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+
+            match schema.parse(record) {
+                Ok(td) => return Some(Ok(td)),
+                Err(e) => {
+                    if let Err(e) = self.malformed(&frame, e) {
+                        return Some(Err(e));
+                    }
+                },
+            }
+        }
+    }
+}
+
+/*
+ * Records for the tests elsewhere, with only the fields that matter to them
+ * filled in. Times are given in milliseconds after an arbitrary instant.
+ */
+#[cfg(test)]
+pub mod testing {
+    use std::collections::BTreeMap;
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    use super::{CallStats, HttpDetails, TraceData};
+
+    pub fn at(ms: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_587_405_600, 0) + Duration::milliseconds(ms)
+    }
+
+    /*
+     * A successful call of 'api' on 'host', from 'begin' to 'end'. The trace
+     * type is taken from the API's prefix, e.g. 's3' for 's3.GetObject'.
+     */
+    pub fn call(api: &str, host: &str, begin: i64, end: i64) -> TraceData {
+        TraceData {
+            trace_type: api.split('.').next().unwrap_or_default()
+                .to_string(),
+            host: host.to_string(),
+            time: at(end),
+            client: "client".to_string(),
+            call_stats: CallStats {
+                rx: 0,
+                tx: 0,
+                duration: ((end - begin) * 1_000_000) as u64,
+                time_to_first_byte: 0,
+            },
+            api: api.to_string(),
+            path: "/bucket/object".to_string(),
+            query: String::new(),
+            status_code: 200,
+            status_msg: "OK".to_string(),
+            error: String::new(),
+            http: None,
+            request: None,
+        }
+    }
+
+    /*
+     * Make a call look verbosely traced, with an 'X-Amz-Request-Id' header
+     * in its request, its response or both.
+     */
+    pub fn verbose(mut td: TraceData, request: Option<&str>,
+        response: Option<&str>) -> TraceData {
+
+        let headers = |id: Option<&str>| id.into_iter()
+            .map(|id| ("X-Amz-Request-Id".to_string(), vec![id.to_string()]))
+            .collect::<BTreeMap<_, _>>();
+
+        td.http = Some(HttpDetails {
+            method: "GET".to_string(),
+            proto: "HTTP/1.1".to_string(),
+            request_headers: headers(request),
+            response_headers: headers(response),
+        });
+        td
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: &str = concat!(r#"{"host": "h1", "client": "c", "#,
+        r#""callStats": {"rx": 0, "tx": 0, "duration": 1000, "#,
+        r#""timeToFirstByte": 0}, "api": "s3.GetObject", "path": "/b/o", "#,
+        r#""query": "", "statusCode": 200, "statusMsg": "OK", "#,
+        r#""time": "2020-04-20T18:00:00Z"}"#);
+
+    fn records(input: String) -> (Vec<String>, u64) {
+        let skipped = Arc::new(AtomicU64::new(0));
+        let records = Records::new("test", Box::new(io::Cursor::new(input)),
+            None, false, Arc::clone(&skipped));
+        let apis = records.map(|r| r.unwrap().api).collect();
+        (apis, skipped.load(Ordering::Relaxed))
+    }
+
+    /*
+     * A verbose record from an older mc, which names fields in lower case.
+     */
+    const LEGACY_VERBOSE: &str = concat!(r#"{"nodename": "h1:9000", "#,
+        r#""funcname": "s3.PutObject", "request": {"time": "#,
+        r#""2020-04-20T18:00:00Z", "proto": "HTTP/1.1", "method": "PUT", "#,
+        r#""path": "/b/o", "rawquery": "x=1", "client": "c", "#,
+        r#""headers": {"Content-Length": ["5"]}}, "response": {"#,
+        r#""time": "2020-04-20T18:00:01Z", "statuscode": 503, "headers": "#,
+        r#"{"X-Amz-Request-Id": ["A"]}}, "stats": {"inputbytes": 5, "#,
+        r#""outputbytes": 10, "latency": 1000000000, "#,
+        r#""timetofirstbyte": 2000}}"#);
+
+    /*
+     * A record straight from the server, with a numeric type and a start
+     * time.
+     */
+    const TRACE_INFO: &str = concat!(r#"{"type": 8, "nodename": "h2:9000", "#,
+        r#""funcname": "internal.ReadAll", "time": "2020-04-20T18:00:00Z", "#,
+        r#""path": "/d1/b/o", "dur": 250000000, "http": {"request": {"#,
+        r#""method": "POST", "proto": "HTTP/1.1", "client": "h1:9000", "#,
+        r#""headers": {"X-Amz-Request-Id": ["A"]}}, "response": {"#,
+        r#""time": "2020-04-20T18:00:00.25Z", "statuscode": 200}, "#,
+        r#""stats": {"inputbytes": 1, "outputbytes": 2}}}"#);
+
+    fn parse(input: &str) -> TraceData {
+        let skipped = Arc::new(AtomicU64::new(0));
+        let mut records = Records::new("test",
+            Box::new(io::Cursor::new(input.to_string())), None, true,
+            Arc::clone(&skipped));
+        records.next().unwrap().unwrap()
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn reads_legacy() {
+        let td = parse(GOOD);
+        assert_eq!(td.time, time("2020-04-20T18:00:00Z"));
+        assert_eq!(td.call_stats.duration, 1000);
+        assert_eq!(td.trace_type, "s3");
+        assert_eq!(td.status_code, 200);
+        assert!(td.http.is_none());
+    }
+
+    #[test]
+    fn reads_legacy_verbose() {
+        let td = parse(LEGACY_VERBOSE);
+        assert_eq!(td.host, "h1:9000");
+        assert_eq!(td.api, "s3.PutObject");
+        assert_eq!(td.time, time("2020-04-20T18:00:01Z"));
+        assert_eq!(td.start_time(), time("2020-04-20T18:00:00Z"));
+        assert_eq!(td.call_stats.duration, 1_000_000_000);
+        assert_eq!(td.call_stats.time_to_first_byte, 2000);
+        assert_eq!((td.call_stats.rx, td.call_stats.tx), (5, 10));
+        assert_eq!(td.trace_type, "s3");
+        assert_eq!(td.status_code, 503);
+        assert_eq!((td.path.as_str(), td.query.as_str()), ("/b/o", "x=1"));
+
+        let http = td.http.as_ref().unwrap();
+        assert_eq!((http.method.as_str(), http.proto.as_str()),
+            ("PUT", "HTTP/1.1"));
+        assert_eq!(http.request_headers["Content-Length"], vec!["5"]);
+        assert_eq!(td.request_id(), Some("A"));
+    }
+
+    #[test]
+    fn reads_trace_info() {
+        let td = parse(TRACE_INFO);
+        assert_eq!(td.host, "h2:9000");
+        assert_eq!(td.api, "internal.ReadAll");
+
+        /* The record gives the start time, but 'time' is the end. */
+        assert_eq!(td.time, time("2020-04-20T18:00:00.25Z"));
+        assert_eq!(td.start_time(), time("2020-04-20T18:00:00Z"));
+        assert_eq!(td.call_stats.duration, 250_000_000);
+        assert_eq!((td.call_stats.rx, td.call_stats.tx), (1, 2));
+        assert_eq!(td.trace_type, "internal");
+        assert_eq!(td.status_code, 200);
+        assert_eq!(td.client, "h1:9000");
+        assert_eq!(td.path, "/d1/b/o");
+
+        let http = td.http.as_ref().unwrap();
+        assert_eq!(http.method, "POST");
+        assert_eq!(td.request_id(), Some("A"));
+    }
+
+    #[test]
+    fn names_trace_types() {
+        let td = parse(concat!(r#"{"type": 2, "nodename": "h2:9000", "#,
+            r#""funcname": "storage.ReadAll", "time": "#,
+            r#""2020-04-20T18:00:00Z", "path": "/d1/b/o", "dur": 1000}"#));
+        assert_eq!(td.trace_type, "storage");
+        assert_eq!(td.status_code, 0);
+        assert!(td.http.is_none());
+
+        let named = TRACE_INFO.replace(r#""type": 8"#, r#""type": "OS""#);
+        assert_eq!(parse(&named).trace_type, "os");
+    }
+
+    #[test]
+    fn skips_truncated_records() {
+        let cut = &GOOD[..60];
+        let input = format!("{}\n{}\n{}", GOOD, cut, cut);
+        assert_eq!(records(input), (vec!["s3.GetObject".to_string()], 2));
+    }
+
+    #[test]
+    fn recovers_record_after_truncation_on_same_line() {
+        let input = format!("{}\n{}{}\n{}\n", GOOD, &GOOD[..60], GOOD, GOOD);
+        let (apis, skipped) = records(input);
+        assert_eq!(apis.len(), 3);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn skips_junk() {
+        let input = format!("not json\n{}\n", GOOD);
+        assert_eq!(records(input), (vec!["s3.GetObject".to_string()], 1));
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+
+use crate::trace::TraceData;
+
+/*
+ * One end of a time window: either an absolute time or an offset from the
+ * start of the first trace record.
+ */
+#[derive(Clone, Copy)]
+pub enum Bound {
+    Absolute(DateTime<Utc>),
+    Offset(Duration),
+}
+
+impl FromStr for Bound {
+    type Err = String;
+
+    /*
+     * Accepts an RFC 3339 timestamp, or a duration such as '30s', '+1m30s',
+     * '1.5s' or '250ms'. A bare number is taken to be seconds.
+     */
+    fn from_str(s: &str) -> Result<Bound, String> {
+        if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Bound::Absolute(t.with_timezone(&Utc)));
+        }
+
+        parse_duration(s.trim_start_matches('+'))
+            .map(Bound::Offset)
+            .ok_or_else(|| format!("invalid time '{}': expected an RFC 3339 \
+                timestamp or an offset such as '30s'", s))
+    }
+}
+
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    if s.is_empty() {
+        return None;
+    }
+
+    /*
+     * A bare number is seconds. f64's parser would also take signs,
+     * exponents and 'inf', so make sure that's all it is first.
+     */
+    if s.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        let secs: f64 = s.parse().ok()?;
+        return nanoseconds(secs * 1e9);
+    }
+
+    let mut total = Duration::zero();
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let num: f64 = rest[..num_len].parse().ok()?;
+        rest = &rest[num_len..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let ns_per_unit = match &rest[..unit_len] {
+            "ns" => 1.0,
+            "us" => 1e3,
+            "ms" => 1e6,
+            "s" => 1e9,
+            "m" => 60e9,
+            "h" => 3600e9,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+
+        total = total.checked_add(&nanoseconds(num * ns_per_unit)?)?;
+    }
+
+    Some(total)
+}
+
+/*
+ * A Duration of 'ns' nanoseconds, or None if that's too long for one. A
+ * plain cast would quietly clamp it instead.
+ */
+fn nanoseconds(ns: f64) -> Option<Duration> {
+    if ns >= i64::MAX as f64 {
+        return None;
+    }
+    Some(Duration::nanoseconds(ns as i64))
+}
+
+/*
+ * Window limits conversion to the operations that overlap a period of time.
+ * Operations that straddle either end are clipped to the window rather than
+ * dropped, so the statemap shows the entity busy right up to the edge.
+ *
+ * Offset bounds are resolved against the start of the first record in the
+ * input, as noted by 'origin'.
+ */
+#[derive(Default)]
+pub struct Window {
+    start: Option<Bound>,
+    end: Option<Bound>,
+    origin: Origin,
+}
+
+/*
+ * Origin notes the start of the first record in the input. Records can be
+ * dropped, by sampling for one, before they reach the window, so this is
+ * done as they're read rather than by the window itself.
+ */
+#[derive(Clone, Default)]
+pub struct Origin(Arc<OnceLock<DateTime<Utc>>>);
+
+impl Origin {
+    pub fn note(&self, td: &TraceData) {
+        self.0.get_or_init(|| td.start_time());
+    }
+}
+
+impl Window {
+    pub fn new(start: Option<Bound>, end: Option<Bound>, origin: Origin)
+        -> Window {
+
+        Window { start, end, origin }
+    }
+
+    fn resolve(&self, bound: Option<Bound>) -> Option<DateTime<Utc>> {
+        match bound? {
+            Bound::Absolute(t) => Some(t),
+            Bound::Offset(d) => self.origin.0.get()
+                .and_then(|o| o.checked_add_signed(d)),
+        }
+    }
+
+    /*
+     * Clip the interval [begin, end] to the window, or return None if the two
+     * don't overlap at all.
+     */
+    pub fn clip(&mut self, begin: DateTime<Utc>, end: DateTime<Utc>)
+        -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+
+        self.origin.0.get_or_init(|| begin);
+
+        let mut begin = begin;
+        let mut end = end;
+
+        if let Some(ws) = self.resolve(self.start) {
+            if end < ws {
+                return None;
+            }
+            begin = begin.max(ws);
+        }
+        if let Some(we) = self.resolve(self.end) {
+            if begin > we {
+                return None;
+            }
+            end = end.min(we);
+        }
+
+        Some((begin, end))
+    }
+
+    /*
+     * Describe the window for the statemap metadata, or None if no window
+     * was asked for.
+     */
+    pub fn metadata(&self) -> Option<Value> {
+        if self.start.is_none() && self.end.is_none() {
+            return None;
+        }
+
+        let describe = |bound| self.resolve(bound).map(|t| t.to_rfc3339());
+
+        Some(json!({
+            "start": describe(self.start),
+            "end": describe(self.end),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::testing::{at, call};
+
+    #[test]
+    fn parses_durations() {
+        let ms = Duration::milliseconds;
+        assert_eq!(parse_duration("30"), Some(ms(30_000)));
+        assert_eq!(parse_duration("1.5"), Some(ms(1_500)));
+        assert_eq!(parse_duration("250ms"), Some(ms(250)));
+        assert_eq!(parse_duration("1m30s"), Some(ms(90_000)));
+        assert_eq!(parse_duration("1h0.5m"), Some(ms(3_630_000)));
+        assert_eq!(parse_duration("10us"), Some(Duration::microseconds(10)));
+        assert_eq!(parse_duration("7ns"), Some(Duration::nanoseconds(7)));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for s in &["", "s", "5x", "5 s", "1.2.3s", "-5", "-5s", "1e3", "inf",
+            "NaN", "99999999999", "9999999999999h",
+            "99999999999h99999999999h"] {
+            assert_eq!(parse_duration(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn clips_to_window() {
+        let mut window = Window::new(Some(Bound::Offset(
+            Duration::milliseconds(100))), Some(Bound::Absolute(at(300))),
+            Origin::default());
+
+        assert_eq!(window.clip(at(0), at(50)), None);
+        assert_eq!(window.clip(at(50), at(150)), Some((at(100), at(150))));
+        assert_eq!(window.clip(at(200), at(400)), Some((at(200), at(300))));
+        assert_eq!(window.clip(at(350), at(400)), None);
+    }
+
+    #[test]
+    fn offsets_from_first_record_read() {
+        /*
+         * The first record read was dropped before it reached the window,
+         * but offsets are still from its start.
+         */
+        let origin = Origin::default();
+        origin.note(&call("s3.GetObject", "h1", 0, 10));
+        let mut window = Window::new(None,
+            Some(Bound::Offset(Duration::milliseconds(100))), origin);
+
+        assert_eq!(window.clip(at(50), at(150)), Some((at(50), at(100))));
+    }
+}
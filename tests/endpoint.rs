@@ -0,0 +1,103 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+/*
+ * Run minio-statemap against the example trace API stub, to check what the
+ * admin client asks for and what comes back through it.
+ */
+
+#[path = "../examples/trace_stub.rs"]
+#[allow(dead_code)]
+mod trace_stub;
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process::{self, Command};
+use std::thread;
+
+use serde_json::{json, Value};
+
+/* A trace record in the format MinIO used before trace types. */
+fn record(host: &str, client: &str, api: &str, end_ms: u32, duration_ms: u64)
+    -> String {
+
+    json!({
+        "host": host,
+        "client": client,
+        "callStats": {
+            "rx": 0,
+            "tx": 0,
+            "duration": duration_ms * 1_000_000,
+            "timeToFirstByte": 0,
+        },
+        "api": api,
+        "path": "/b/o",
+        "query": "",
+        "statusCode": 200,
+        "statusMsg": "OK",
+        "time": format!("2020-04-20T18:00:00.{:03}Z", end_ms),
+    }).to_string()
+}
+
+#[test]
+fn traces_endpoint() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let trace = [
+        record("h2:9000", "h1:9000", "internal.ReadFile", 3, 2),
+        record("h1:9000", "c", "s3.GetObject", 8, 8),
+        record("h1:9000", "c", "s3.PutObject", 10, 1),
+    ].join("\n");
+    let stub = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        trace_stub::serve(stream, &trace).unwrap()
+    });
+
+    let dir = env::temp_dir().join(format!("minio-statemap-test-{}",
+        process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let prefix = dir.join("states");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_minio-statemap"))
+        .args(["--endpoint", &format!("http://{}", addr)])
+        .args(["--access-key", "admin", "--secret-key", "secret"])
+        .arg("--output-prefix").arg(&prefix)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}",
+        String::from_utf8_lossy(&output.stderr));
+
+    let (request_line, authorization) = stub.join().unwrap();
+    assert_eq!(request_line,
+        "GET /minio/admin/v3/trace?all=true&err=false HTTP/1.1");
+    let authorization = authorization.expect("no Authorization header");
+    assert!(authorization.starts_with(
+        "AWS4-HMAC-SHA256 Credential=admin/"), "{}", authorization);
+    assert!(authorization.contains("/us-east-1/s3/aws4_request"));
+
+    /* The trace is replayed quickly enough to fit one statemap. */
+    let text = fs::read_to_string(dir.join("states.000001")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let records: Vec<Value> = text.lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+
+    let states: BTreeSet<&str> = records.iter()
+        .filter_map(|r| r["states"].as_object())
+        .flat_map(|s| s.keys().map(|k| k.as_str()))
+        .collect();
+    assert!(["internal.ReadFile", "s3.GetObject", "s3.PutObject"].iter()
+        .all(|s| states.contains(s)), "{:?}", states);
+
+    let entities: BTreeSet<&str> = records.iter()
+        .filter_map(|r| r["entity"].as_str())
+        .collect();
+    assert_eq!(entities, ["h1:9000", "h2:9000"].iter().cloned().collect());
+}